@@ -0,0 +1,815 @@
+/**
+    tray (c) dotHQ 2021
+    A standalone tray application
+
+    A single app indicator in the tray. Owns the button that represents a status notifier
+    item and keeps it up to date as the item signals changes.
+
+    This Source Code Form is subject to the terms of the Mozilla Public
+    License, v. 2.0. If a copy of the MPL was not distributed with this
+    file, You can obtain one at http://mozilla.org/MPL/2.0/.
+*/
+use std::{
+    cell::{Cell, RefCell},
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use dbus::{blocking::Connection, channel::Token};
+use gdk::{prelude::*, EventMask, EventType, Gravity, ScrollDirection, WindowExt};
+use gdk_pixbuf::{prelude::*, InterpType, Pixbuf, PixbufAnimationIter};
+use glib::Continue;
+use gtk::{prelude::*, ButtonBuilder, IconSize, IconTheme, Image, Inhibit};
+
+use crate::{
+    dbus_menu::{self, MenuProperty},
+    dbus_source,
+    error::TrayError,
+    icon::{self, IconSource},
+    item_properties::{ItemProperties, Status},
+    menu::Menu,
+    pixmap,
+    settings::Settings,
+    status_notifier_host::StatusNotifierItem,
+};
+
+// The size of the icon on the button in pixels
+const ICON_PIXEL_SIZE: i32 = 16;
+const TOOL_TIP_ICON_SIZE: IconSize = IconSize::Dialog;
+// The size of `TOOL_TIP_ICON_SIZE` in pixels
+const TOOL_TIP_ICON_PIXEL_SIZE: i32 = 48;
+// The delta of one step of a mouse wheel, as Qt reports it
+const SCROLL_STEP: i32 = 120;
+// How long each of the two icons is shown when blinking, in milliseconds
+const BLINK_INTERVAL: u32 = 500;
+// Apps can be slow to answer while they start up, so the first load waits longer
+const LOAD_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Events that are sent from D-Bus signal handlers to the GTK main loop. Item events carry
+/// the register string of the item they belong to.
+pub enum Event {
+    ItemRegistered(String),
+    // An item that timed out while loading, with the number of the next attempt
+    RetryItem(String, u32),
+    ItemUnregistered(String),
+    NewIcon(String),
+    NewAttentionIcon(String),
+    NewOverlayIcon(String),
+    NewStatus(String, Status),
+    NewIconThemePath(String),
+    ScaleFactorChanged(String),
+    NewTitle(String),
+    NewToolTip(String),
+    MenuLayoutUpdated(String, u32, i32),
+    MenuPropertiesUpdated(String, Vec<(i32, Vec<MenuProperty>)>),
+}
+
+impl Event {
+    /// The register string of the item the event is about
+    pub fn indicator(&self) -> &str {
+        match self {
+            Event::ItemRegistered(indicator)
+            | Event::RetryItem(indicator, _)
+            | Event::ItemUnregistered(indicator)
+            | Event::NewIcon(indicator)
+            | Event::NewAttentionIcon(indicator)
+            | Event::NewOverlayIcon(indicator)
+            | Event::NewStatus(indicator, _)
+            | Event::NewIconThemePath(indicator)
+            | Event::ScaleFactorChanged(indicator)
+            | Event::NewTitle(indicator)
+            | Event::NewToolTip(indicator)
+            | Event::MenuLayoutUpdated(indicator, _, _)
+            | Event::MenuPropertiesUpdated(indicator, _) => indicator,
+        }
+    }
+}
+
+/// What is displayed when hovering over an app indicator
+#[derive(Default)]
+struct ToolTipContent {
+    markup: String,
+    icon_name: Option<String>,
+    icon: Option<Pixbuf>,
+}
+
+pub struct AppIndicator {
+    item: StatusNotifierItem<'static>,
+    connection: &'static Connection,
+    button: gtk::Button,
+    menu: Option<Rc<RefCell<Menu>>>,
+    status: Status,
+    // Only set for items with an IconThemePath, the others use the theme of the user
+    icon_theme: Option<IconTheme>,
+    // Where the icon was found, if it was, once the first icon has been set
+    icon_source: Option<Option<IconSource>>,
+    // Playing while the item needs attention
+    attention: Option<Attention>,
+    // When the item started to need attention, kept while its icon changes
+    attention_since: Option<Instant>,
+    attention_timeout: Option<Duration>,
+    tool_tip: Rc<RefCell<ToolTipContent>>,
+    tokens: Vec<Token>,
+}
+
+impl AppIndicator {
+    pub fn new(
+        indicator: String,
+        connection: &'static Connection,
+        sender: glib::Sender<Event>,
+        settings: Settings,
+    ) -> Result<Self, TrayError> {
+        let mut item = StatusNotifierItem::new(indicator.clone(), connection)?;
+        let properties = item.get_properties_within(LOAD_TIMEOUT)?;
+        item.set_menu(properties.menu.as_ref());
+        // Items that leave out their status are shown, rather than lost
+        let status = properties.status.clone().unwrap_or(Status::Active);
+        let icon_theme = item_icon_theme(&properties);
+
+        // Create the button for the app indicator. We will not display app indicators
+        // that are in a passive state
+        let button = ButtonBuilder::new().visible(status.is_visible()).build();
+
+        // Icons are rendered for the scale of the monitor the tray is on
+        let (scale_indicator, scale_sender) = (indicator.clone(), sender.clone());
+        button.connect_property_scale_factor_notify(move |_| {
+            let _ = scale_sender.send(Event::ScaleFactorChanged(scale_indicator.clone()));
+        });
+
+        // Items with a DBusMenu get it as a native menu when right-clicking. The layout is
+        // fetched when it is first shown, and kept up to date from then on.
+        let menu = item
+            .menu()
+            .map(|dbus_menu| Menu::new(dbus_menu.clone(), &button));
+
+        // Items that are only a menu show it on a primary click as well. Ayatana items do
+        // not have ItemIsMenu (nor Activate), so having a menu is enough for them.
+        let is_menu = properties.item_is_menu.unwrap_or_else(|| menu.is_some());
+
+        let click_handler = Rc::new(ClickHandler {
+            item: item.to_owned(),
+            menu: menu.clone(),
+            is_menu,
+        });
+
+        let press_handler = click_handler.clone();
+        button.connect_button_press_event(move |button, event| {
+            // Double clicks also send a press for each click, which are handled already
+            if event.get_event_type() != EventType::ButtonPress {
+                return Inhibit(false);
+            }
+
+            press_handler.click(button, event.get_button(), Some(event));
+            Inhibit(true)
+        });
+
+        // Only reached when activating the button with the keyboard
+        button.connect_clicked(move |button| click_handler.click(button, 1, None));
+
+        // Wheel input over the icon goes to the app, e.g. to change the volume
+        let scroll_handler = ScrollHandler {
+            item: item.to_owned(),
+            invert: settings.invert_scroll,
+            smooth_delta: Cell::new((0.0, 0.0)),
+        };
+        button.add_events(EventMask::SCROLL_MASK | EventMask::SMOOTH_SCROLL_MASK);
+        button.connect_scroll_event(move |_, event| {
+            scroll_handler.scroll(event);
+            Inhibit(true)
+        });
+
+        // The tooltip is built when it is about to be shown, so it can carry an icon
+        let tool_tip = Rc::new(RefCell::new(ToolTipContent::default()));
+        let tool_tip_content = tool_tip.clone();
+        button.set_has_tooltip(true);
+        button.connect_query_tooltip(move |_, _, _, _, tooltip| {
+            let content = tool_tip_content.borrow();
+
+            if content.markup.is_empty() {
+                return false;
+            }
+
+            tooltip.set_markup(Some(&content.markup));
+            if let Some(icon) = &content.icon {
+                tooltip.set_icon(Some(icon));
+            } else {
+                tooltip.set_icon_from_icon_name(content.icon_name.as_deref(), TOOL_TIP_ICON_SIZE);
+            }
+
+            true
+        });
+
+        let mut app_indicator = AppIndicator {
+            item,
+            connection,
+            button,
+            menu,
+            status,
+            icon_theme,
+            icon_source: None,
+            attention: None,
+            attention_since: None,
+            attention_timeout: settings.attention_timeout,
+            tool_tip,
+            tokens: Vec::new(),
+        };
+
+        app_indicator.set_icon(&properties);
+        app_indicator.set_tool_tip(&properties);
+        app_indicator.subscribe(indicator, sender)?;
+
+        Ok(app_indicator)
+    }
+
+    pub fn button(&self) -> &gtk::Button {
+        &self.button
+    }
+
+    /// Listens for changes to the item, which are sent back to the main loop as events
+    fn subscribe(
+        &mut self,
+        indicator: String,
+        sender: glib::Sender<Event>,
+    ) -> Result<(), TrayError> {
+        let (event_indicator, event_sender) = (indicator.clone(), sender.clone());
+        let token = self.item.new_icon(move |_, _, _| {
+            let _ = event_sender.send(Event::NewIcon(event_indicator.clone()));
+            true
+        })?;
+        self.tokens.push(token);
+
+        let (event_indicator, event_sender) = (indicator.clone(), sender.clone());
+        let token = self.item.new_attention_icon(move |_, _, _| {
+            let _ = event_sender.send(Event::NewAttentionIcon(event_indicator.clone()));
+            true
+        })?;
+        self.tokens.push(token);
+
+        let (event_indicator, event_sender) = (indicator.clone(), sender.clone());
+        let token = self.item.new_overlay_icon(move |_, _, _| {
+            let _ = event_sender.send(Event::NewOverlayIcon(event_indicator.clone()));
+            true
+        })?;
+        self.tokens.push(token);
+
+        let (event_indicator, event_sender) = (indicator.clone(), sender.clone());
+        let token = self.item.new_status(move |signal, _, _| {
+            let _ = event_sender.send(Event::NewStatus(
+                event_indicator.clone(),
+                signal.status.parse().unwrap_or(Status::Active),
+            ));
+            true
+        })?;
+        self.tokens.push(token);
+
+        let (event_indicator, event_sender) = (indicator.clone(), sender.clone());
+        let token = self.item.new_icon_theme_path(move |_, _, _| {
+            let _ = event_sender.send(Event::NewIconThemePath(event_indicator.clone()));
+            true
+        })?;
+        self.tokens.push(token);
+
+        let (event_indicator, event_sender) = (indicator.clone(), sender.clone());
+        let token = self.item.new_title(move |_, _, _| {
+            let _ = event_sender.send(Event::NewTitle(event_indicator.clone()));
+            true
+        })?;
+        self.tokens.push(token);
+
+        let (event_indicator, event_sender) = (indicator.clone(), sender.clone());
+        let token = self.item.new_tool_tip(move |_, _, _| {
+            let _ = event_sender.send(Event::NewToolTip(event_indicator.clone()));
+            true
+        })?;
+        self.tokens.push(token);
+
+        if let Some(dbus_menu) = self.item.menu() {
+            let (event_indicator, event_sender) = (indicator.clone(), sender.clone());
+            let token = dbus_menu.layout_updated(move |signal, _, _| {
+                let _ = event_sender.send(Event::MenuLayoutUpdated(
+                    event_indicator.clone(),
+                    signal.revision,
+                    signal.parent,
+                ));
+                true
+            })?;
+            self.tokens.push(token);
+
+            // The properties are parsed here, as the raw values cannot leave this thread
+            let token = dbus_menu.items_properties_updated(move |signal, _, _| {
+                let _ = sender.send(Event::MenuPropertiesUpdated(
+                    indicator.clone(),
+                    dbus_menu::property_updates(signal),
+                ));
+                true
+            })?;
+            self.tokens.push(token);
+        }
+
+        Ok(())
+    }
+
+    /// Reloads the icon that matches the current status
+    pub fn update_icon(&mut self) -> Result<(), TrayError> {
+        let properties = self.item.get_properties()?;
+        self.set_icon(&properties);
+
+        Ok(())
+    }
+
+    /// Looks up the icon in the new theme path of the item
+    pub fn update_icon_theme_path(&mut self) -> Result<(), TrayError> {
+        let properties = self.item.get_properties()?;
+        self.icon_theme = item_icon_theme(&properties);
+        self.set_icon(&properties);
+
+        Ok(())
+    }
+
+    fn set_icon(&mut self, properties: &ItemProperties) {
+        // Stops the animation of the previous icon
+        self.attention = None;
+
+        let icon_theme = self.icon_theme.as_ref();
+        let scale = self.button.get_scale_factor();
+        let icon = icon_pixbuf(properties, &self.status, icon_theme, scale);
+
+        // Icons are set again on every change, so only a different source is worth logging
+        let source = icon.as_ref().map(|(_, source)| *source);
+        if self.icon_source != Some(source) {
+            let id = properties.id.as_deref().unwrap_or_default();
+            match source {
+                Some(source) => eprintln!("Icon of {:?} found by {}", id, source),
+                None => eprintln!("No icon found for {:?}", id),
+            }
+            self.icon_source = Some(source);
+        }
+        let icon = icon.map(|(pixbuf, _)| pixbuf);
+
+        let image = Image::new();
+        show_pixbuf(&image, icon.as_ref(), scale);
+
+        if self.status == Status::NeedsAttention {
+            // The timeout counts from when the item started to need attention, not from
+            // when its icon last changed
+            let since = *self.attention_since.get_or_insert_with(Instant::now);
+            let remaining = self
+                .attention_timeout
+                .map(|timeout| timeout.saturating_sub(since.elapsed()));
+
+            if remaining != Some(Duration::ZERO) {
+                self.attention = Some(Attention::start(
+                    &image, icon, properties, icon_theme, scale, remaining,
+                ));
+            }
+        }
+
+        self.button.set_image(Some(&image));
+    }
+
+    /// Only reloads the icon if the attention icon is the one being displayed
+    pub fn update_attention_icon(&mut self) -> Result<(), TrayError> {
+        if self.status == Status::NeedsAttention {
+            self.update_icon()?;
+        }
+
+        Ok(())
+    }
+
+    pub fn update_status(&mut self, status: Status) -> Result<(), TrayError> {
+        // Swapping between the normal and attention icon is only needed when moving
+        // into or out of NeedsAttention
+        let icon_changed =
+            (self.status == Status::NeedsAttention) != (status == Status::NeedsAttention);
+
+        if self.status != status {
+            self.attention_since = None;
+        }
+
+        self.button.set_visible(status.is_visible());
+        self.status = status;
+
+        if icon_changed {
+            self.update_icon()?;
+        }
+
+        Ok(())
+    }
+
+    /// Fetches the part of the menu that changed. The menu is a separate object of the app,
+    /// so failing to refresh it says nothing about the item and is only logged.
+    pub fn update_menu_layout(&self, revision: u32, parent: i32) {
+        if let Some(menu) = &self.menu {
+            if let Err(error) = menu.borrow_mut().update_layout(revision, parent) {
+                eprintln!("Failed to update the menu of {:?}: {}", self.item, error);
+            }
+        }
+    }
+
+    pub fn update_menu_properties(&self, updates: Vec<(i32, Vec<MenuProperty>)>) {
+        if let Some(menu) = &self.menu {
+            menu.borrow_mut().update_properties(updates);
+        }
+    }
+
+    /// Reloads the tooltip of the button
+    pub fn update_tool_tip(&self) -> Result<(), TrayError> {
+        self.set_tool_tip(&self.item.get_properties()?);
+
+        Ok(())
+    }
+
+    /// Items without a ToolTip get their title instead
+    fn set_tool_tip(&self, properties: &ItemProperties) {
+        let mut content = ToolTipContent::default();
+
+        match &properties.tool_tip {
+            Some(tool_tip) if !tool_tip.is_empty() => {
+                content.markup = tool_tip.to_markup();
+
+                if !tool_tip.icon_name.is_empty() {
+                    content.icon_name = Some(tool_tip.icon_name.clone());
+                } else {
+                    content.icon =
+                        pixmap::pixbuf_for_size(&tool_tip.icon_pixmap, TOOL_TIP_ICON_PIXEL_SIZE);
+                }
+            }
+            _ => {
+                let title = properties.title.as_deref().unwrap_or_default();
+                content.markup = glib::markup_escape_text(title).to_string();
+            }
+        }
+
+        *self.tool_tip.borrow_mut() = content;
+        self.button.trigger_tooltip_query();
+    }
+}
+
+impl Drop for AppIndicator {
+    fn drop(&mut self) {
+        // Stop listening for signals of an item that is no longer displayed
+        for token in self.tokens.drain(..) {
+            let _ = self.connection.remove_match(token);
+        }
+
+        // Removing the match rules blocked until the bus daemon answered
+        dbus_source::dispatch_queued(self.connection);
+    }
+}
+
+/// Dispatches clicks on the button of an app indicator, following the StatusNotifierItem spec
+struct ClickHandler {
+    item: StatusNotifierItem<'static>,
+    menu: Option<Rc<RefCell<Menu>>>,
+    is_menu: bool,
+}
+
+impl ClickHandler {
+    fn click(&self, button: &gtk::Button, mouse_button: u32, event: Option<&gdk::EventButton>) {
+        let (x, y) = click_position(button, event);
+
+        // Items that do not implement a method get the next best thing instead
+        let result = match mouse_button {
+            1 if !self.is_menu => self.item.activate(x, y).or_else(|error| match error {
+                TrayError::UnknownMethod(_) => self.open_menu(button, x, y, event),
+                error => Err(error),
+            }),
+            1 | 3 => self.open_menu(button, x, y, event),
+            2 => self
+                .item
+                .secondary_activate(x, y)
+                .or_else(|error| match error {
+                    TrayError::UnknownMethod(_) => {
+                        let timestamp = event
+                            .map_or_else(gtk::get_current_event_time, |event| event.get_time());
+                        self.item.x_ayatana_secondary_activate(timestamp)
+                    }
+                    error => Err(error),
+                }),
+            _ => Ok(()),
+        };
+
+        match result {
+            Ok(()) => {}
+            Err(TrayError::UnknownMethod(_)) => {
+                eprintln!("{:?} does not handle click {}", self.item, mouse_button)
+            }
+            Err(error) => eprintln!(
+                "Failed to handle click {} on {:?}: {}",
+                mouse_button, self.item, error
+            ),
+        }
+    }
+
+    /// Shows the DBusMenu of the item, or asks the item to show its own menu when there is
+    /// no DBusMenu or it cannot be loaded
+    fn open_menu(
+        &self,
+        button: &gtk::Button,
+        x: i32,
+        y: i32,
+        event: Option<&gdk::EventButton>,
+    ) -> Result<(), TrayError> {
+        let menu = match &self.menu {
+            Some(menu) => menu,
+            None => return self.item.context_menu(x, y),
+        };
+
+        show_menu(menu, button, event.map(|event| &**event)).or_else(|error| {
+            eprintln!("Failed to show the menu of {:?}: {}", self.item, error);
+            self.item.context_menu(x, y)
+        })
+    }
+}
+
+/// Forwards scrolling over the button of an app indicator to the app
+struct ScrollHandler {
+    item: StatusNotifierItem<'static>,
+    invert: bool,
+    // Touchpads scroll in fractions of a step, which are added up until they make full steps
+    smooth_delta: Cell<(f64, f64)>,
+}
+
+impl ScrollHandler {
+    fn scroll(&self, event: &gdk::EventScroll) {
+        // In steps to the right and down, like GDK counts them
+        let (steps_x, steps_y) = match event.get_direction() {
+            ScrollDirection::Up => (0.0, -1.0),
+            ScrollDirection::Down => (0.0, 1.0),
+            ScrollDirection::Left => (-1.0, 0.0),
+            ScrollDirection::Right => (1.0, 0.0),
+            ScrollDirection::Smooth => {
+                let (delta_x, delta_y) = event.get_delta();
+                let (total_x, total_y) = self.smooth_delta.get();
+                let (total_x, total_y) = (total_x + delta_x, total_y + delta_y);
+                let steps = (total_x.trunc(), total_y.trunc());
+
+                self.smooth_delta
+                    .set((total_x - steps.0, total_y - steps.1));
+                steps
+            }
+            _ => (0.0, 0.0),
+        };
+
+        // Apps expect Qt's wheel deltas, which are positive when scrolling up
+        let direction = if self.invert { -1 } else { 1 };
+        let deltas = [
+            (steps_x as i32 * SCROLL_STEP * direction, "horizontal"),
+            (-steps_y as i32 * SCROLL_STEP * direction, "vertical"),
+        ];
+
+        for &(delta, orientation) in deltas.iter().filter(|(delta, _)| *delta != 0) {
+            if let Err(error) = self.item.scroll(delta, orientation) {
+                eprintln!("Failed to scroll {:?}: {}", self.item, error);
+            }
+        }
+    }
+}
+
+/// Where a click happened in screen coordinates, which apps use to place their own windows
+/// next to the tray. Wayland does not tell clients where they are on the screen, and
+/// keyboard activation has no position at all, so there the corner of the button is used.
+fn click_position(button: &gtk::Button, event: Option<&gdk::EventButton>) -> (i32, i32) {
+    let is_wayland = button.get_display().get_type().name() == "GdkWaylandDisplay";
+
+    match event {
+        Some(event) if !is_wayland => {
+            let (x, y) = event.get_root();
+            (x as i32, y as i32)
+        }
+        _ => {
+            let allocation = button.get_allocation();
+            let (_, origin_x, origin_y) = button
+                .get_window()
+                .map_or((0, 0, 0), |window| window.get_origin());
+
+            (
+                origin_x + allocation.x,
+                origin_y + allocation.y + allocation.height,
+            )
+        }
+    }
+}
+
+/// Pops up the menu of an app indicator below its button
+fn show_menu(
+    menu: &RefCell<Menu>,
+    button: &gtk::Button,
+    trigger_event: Option<&gdk::Event>,
+) -> Result<(), TrayError> {
+    let mut menu = menu.borrow_mut();
+    menu.prepare()?;
+
+    menu.gtk_menu().popup_at_widget(
+        button,
+        Gravity::SouthWest,
+        Gravity::NorthWest,
+        trigger_event,
+    );
+
+    Ok(())
+}
+
+/// Draws attention to an item by playing its AttentionMovieName, or by blinking between
+/// its normal and attention icon when it has no movie. Stops when dropped, or once the
+/// timeout is over.
+struct Attention {
+    playing: Rc<Cell<bool>>,
+}
+
+impl Attention {
+    /// Starts animating `image`, which shows `attention_icon`
+    fn start(
+        image: &Image,
+        attention_icon: Option<Pixbuf>,
+        properties: &ItemProperties,
+        icon_theme: Option<&IconTheme>,
+        scale: i32,
+        timeout: Option<Duration>,
+    ) -> Self {
+        let playing = Rc::new(Cell::new(true));
+
+        let movie_name = properties
+            .attention_movie_name
+            .as_deref()
+            .unwrap_or_default();
+        match icon::load_animation(movie_name, icon_theme, ICON_PIXEL_SIZE * scale) {
+            Some(animation) => {
+                // GtkImage shows animations at the size they come in, so the frames are
+                // scaled and shown one by one instead
+                let frames = animation.get_iter(glib::get_current_time());
+                play_animation(image.clone(), frames, scale, playing.clone());
+            }
+            None => {
+                // Items without an attention icon blink by hiding their icon instead
+                let icon = icon_pixbuf(properties, &Status::Active, icon_theme, scale)
+                    .map(|(pixbuf, _)| pixbuf);
+                let swap_icons = has_attention_icon(properties);
+                let (image, playing, attention_icon) =
+                    (image.clone(), playing.clone(), attention_icon.clone());
+                let mut showing_attention = true;
+
+                glib::timeout_add_local(BLINK_INTERVAL, move || {
+                    if !playing.get() {
+                        return Continue(false);
+                    }
+
+                    showing_attention = !showing_attention;
+                    if swap_icons {
+                        let shown = if showing_attention {
+                            &attention_icon
+                        } else {
+                            &icon
+                        };
+                        show_pixbuf(&image, shown.as_ref(), scale);
+                    } else {
+                        image.set_opacity(if showing_attention { 1.0 } else { 0.0 });
+                    }
+
+                    Continue(true)
+                });
+            }
+        }
+
+        // Afterwards the attention icon stays, without the animation
+        if let Some(timeout) = timeout {
+            let (image, playing) = (image.clone(), playing.clone());
+            // Timeouts beyond what GLib takes (about 49 days) are as good as none
+            let interval = timeout.as_millis().min(u32::MAX as u128) as u32;
+            glib::timeout_add_local(interval, move || {
+                if playing.replace(false) {
+                    show_pixbuf(&image, attention_icon.as_ref(), scale);
+                    image.set_opacity(1.0);
+                }
+
+                Continue(false)
+            });
+        }
+
+        Attention { playing }
+    }
+}
+
+impl Drop for Attention {
+    fn drop(&mut self) {
+        self.playing.set(false);
+    }
+}
+
+/// Shows the frames of an animation on `image` one after the other, each for as long as
+/// the animation says, until `playing` is unset
+fn play_animation(image: Image, frames: PixbufAnimationIter, scale: i32, playing: Rc<Cell<bool>>) {
+    if !playing.get() {
+        return;
+    }
+
+    let frame = scale_to_fit(&frames.get_pixbuf(), ICON_PIXEL_SIZE * scale);
+    show_pixbuf(&image, frame.as_ref(), scale);
+
+    // The last frame of animations that do not loop stays forever
+    let delay = frames.get_delay_time();
+    if delay < 0 {
+        return;
+    }
+
+    glib::timeout_add_local(delay as u32, move || {
+        frames.advance(glib::get_current_time());
+        play_animation(image.clone(), frames.clone(), scale, playing.clone());
+        Continue(false)
+    });
+}
+
+/// Scales `pixbuf` to fit into a square of `size`, keeping its aspect ratio
+fn scale_to_fit(pixbuf: &Pixbuf, size: i32) -> Option<Pixbuf> {
+    let largest = pixbuf.get_width().max(pixbuf.get_height());
+    if largest == size {
+        return Some(pixbuf.clone());
+    }
+
+    let width = (pixbuf.get_width() * size / largest).max(1);
+    let height = (pixbuf.get_height() * size / largest).max(1);
+
+    pixbuf.scale_simple(width, height, InterpType::Bilinear)
+}
+
+/// Whether the item has an icon of its own for when it needs attention
+fn has_attention_icon(properties: &ItemProperties) -> bool {
+    let has_name = properties
+        .attention_icon_name
+        .as_deref()
+        .is_some_and(|icon_name| !icon_name.is_empty());
+    let has_pixmap = properties
+        .attention_icon_pixmap
+        .as_deref()
+        .is_some_and(|pixmaps| !pixmaps.is_empty());
+
+    has_name || has_pixmap
+}
+
+/// Creates the icon of the app indicator for the given status, at `scale` times its size so
+/// it is sharp on HiDPI screens, along with where it was found. Items without an attention
+/// icon keep their normal icon when they need attention.
+fn icon_pixbuf(
+    properties: &ItemProperties,
+    status: &Status,
+    icon_theme: Option<&IconTheme>,
+    scale: i32,
+) -> Option<(Pixbuf, IconSource)> {
+    let (icon_name, pixmaps) =
+        if *status == Status::NeedsAttention && has_attention_icon(properties) {
+            (
+                &properties.attention_icon_name,
+                &properties.attention_icon_pixmap,
+            )
+        } else {
+            (&properties.icon_name, &properties.icon_pixmap)
+        };
+
+    let resolved = icon::resolve(
+        properties.id.as_deref().unwrap_or_default(),
+        icon_name.as_deref().unwrap_or_default(),
+        pixmaps.as_deref().unwrap_or_default(),
+        icon_theme,
+        ICON_PIXEL_SIZE * scale,
+    );
+
+    let (pixbuf, source) = resolved?;
+
+    // Apps show states like syncing or errors with a small icon over their own
+    let composited = icon::composite_overlay(
+        &pixbuf,
+        properties.overlay_icon_name.as_deref().unwrap_or_default(),
+        properties
+            .overlay_icon_pixmap
+            .as_deref()
+            .unwrap_or_default(),
+        icon_theme,
+    );
+
+    Some((composited.unwrap_or(pixbuf), source))
+}
+
+/// Shows an icon that was rendered at `scale` times its size. As a surface with the same
+/// scale, it takes up the size of the icon on screen, with one pixel for each device pixel.
+fn show_pixbuf(image: &Image, pixbuf: Option<&Pixbuf>, scale: i32) {
+    let surface = pixbuf.and_then(|pixbuf| pixbuf.create_surface(scale, None::<&gdk::Window>));
+    image.set_from_surface(surface.as_ref());
+}
+
+/// Creates an icon theme for items that ship their own icons. It follows the theme of the
+/// user, with the IconThemePath of the item searched as well, so icons in any of the
+/// standard layouts (`hicolor/48x48/apps`, ...) and formats (SVG, XPM, ...) are found.
+fn item_icon_theme(properties: &ItemProperties) -> Option<IconTheme> {
+    let theme_path = properties.icon_theme_path.as_deref()?;
+    if theme_path.is_empty() {
+        return None;
+    }
+
+    let icon_theme = IconTheme::new();
+    if let Some(screen) = gdk::Screen::get_default() {
+        icon_theme.set_screen(&screen);
+    }
+    icon_theme.append_search_path(theme_path);
+
+    Some(icon_theme)
+}
@@ -0,0 +1,560 @@
+/**
+    tray (c) dotHQ 2021
+    A standalone tray application
+
+    A client for com.canonical.dbusmenu, which is how most tray apps export their menus.
+    The `(ia{sv}av)` layout on the bus is parsed into a typed tree of menu items.
+
+    This Source Code Form is subject to the terms of the Mozilla Public
+    License, v. 2.0. If a copy of the MPL was not distributed with this
+    file, You can obtain one at http://mozilla.org/MPL/2.0/.
+*/
+use std::{fmt, time::Duration};
+
+use dbus::{
+    arg::{cast, Arg, ArgType, Get, Iter, PropMap, ReadAll, RefArg, Variant},
+    blocking::{Connection, Proxy},
+    channel::Token,
+    message::SignalArgs,
+    strings::{BusName, Path, Signature},
+};
+
+use crate::{
+    dbus_source,
+    error::{self, TrayError},
+    interfaces::dbus_menu::{
+        ComCanonicalDbusmenuItemsPropertiesUpdated, ComCanonicalDbusmenuLayoutUpdated,
+    },
+    status_notifier_host::SignalFunction,
+};
+
+const TIMEOUT: Duration = Duration::from_millis(50);
+const INTERFACE: &str = "com.canonical.dbusmenu";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MenuItemType {
+    Standard,
+    Separator,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ToggleType {
+    None,
+    Checkmark,
+    Radio,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ToggleState {
+    Off,
+    On,
+    Indeterminate,
+}
+
+/// A single entry of a menu, with all of its children
+#[derive(Debug, Clone, PartialEq)]
+pub struct MenuItem {
+    pub id: i32,
+    pub item_type: MenuItemType,
+    pub label: String, // May contain an underscore for the mnemonic
+    pub enabled: bool,
+    pub visible: bool,
+    pub icon_name: Option<String>,
+    pub icon_data: Option<Vec<u8>>, // PNG data
+    pub shortcut: Vec<Vec<String>>, // Key combinations, e.g. [["Control", "q"]]
+    pub toggle_type: ToggleType,
+    pub toggle_state: ToggleState,
+    pub children_display: Option<String>, // "submenu" when the item has children
+    pub children: Vec<MenuItem>,
+}
+
+impl MenuItem {
+    /// Creates an item with the default value of every property
+    pub fn new(id: i32) -> Self {
+        MenuItem {
+            id,
+            item_type: MenuItemType::Standard,
+            label: String::new(),
+            enabled: true,
+            visible: true,
+            icon_name: None,
+            icon_data: None,
+            shortcut: Vec::new(),
+            toggle_type: ToggleType::None,
+            toggle_state: ToggleState::Indeterminate,
+            children_display: None,
+            children: Vec::new(),
+        }
+    }
+
+    pub fn from_layout(layout: Layout) -> Self {
+        let mut item = MenuItem::new(layout.id);
+
+        item.update_properties(&layout.properties);
+        item.children = layout
+            .children
+            .into_iter()
+            .map(MenuItem::from_layout)
+            .collect();
+
+        item
+    }
+
+    /// Applies all properties in `properties`. Properties that are unknown are ignored.
+    pub fn update_properties(&mut self, properties: &PropMap) {
+        for (name, value) in properties {
+            if let Some(property) = MenuProperty::parse(name, Some(&*value.0)) {
+                self.apply(property);
+            }
+        }
+    }
+
+    pub fn apply(&mut self, property: MenuProperty) {
+        match property {
+            MenuProperty::Type(item_type) => self.item_type = item_type,
+            MenuProperty::Label(label) => self.label = label,
+            MenuProperty::Enabled(enabled) => self.enabled = enabled,
+            MenuProperty::Visible(visible) => self.visible = visible,
+            MenuProperty::IconName(icon_name) => self.icon_name = icon_name,
+            MenuProperty::IconData(icon_data) => self.icon_data = icon_data,
+            MenuProperty::Shortcut(shortcut) => self.shortcut = shortcut,
+            MenuProperty::ToggleType(toggle_type) => self.toggle_type = toggle_type,
+            MenuProperty::ToggleState(toggle_state) => self.toggle_state = toggle_state,
+            MenuProperty::ChildrenDisplay(children_display) => {
+                self.children_display = children_display
+            }
+        }
+    }
+
+    /// Whether the item opens a submenu
+    pub fn is_submenu(&self) -> bool {
+        self.children_display.as_deref() == Some("submenu") || !self.children.is_empty()
+    }
+
+    /// Finds the item with the given id in this tree
+    pub fn find(&self, id: i32) -> Option<&MenuItem> {
+        if self.id == id {
+            return Some(self);
+        }
+
+        self.children.iter().find_map(|child| child.find(id))
+    }
+
+    pub fn find_mut(&mut self, id: i32) -> Option<&mut MenuItem> {
+        if self.id == id {
+            return Some(self);
+        }
+
+        self.children
+            .iter_mut()
+            .find_map(|child| child.find_mut(id))
+    }
+}
+
+/// A single typed property of a menu item. Unlike the raw values on the bus, these can be
+/// sent between threads.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MenuProperty {
+    Type(MenuItemType),
+    Label(String),
+    Enabled(bool),
+    Visible(bool),
+    IconName(Option<String>),
+    IconData(Option<Vec<u8>>),
+    Shortcut(Vec<Vec<String>>),
+    ToggleType(ToggleType),
+    ToggleState(ToggleState),
+    ChildrenDisplay(Option<String>),
+}
+
+impl MenuProperty {
+    /// Parses a property by name. A missing value, or one with an unexpected type, gives
+    /// the default value of the property. Unknown properties are `None`.
+    pub fn parse(name: &str, value: Option<&(dyn RefArg + 'static)>) -> Option<Self> {
+        let string = value.and_then(|value| value.as_str()).map(str::to_string);
+        let boolean = value.and_then(|value| cast::<bool>(value).copied());
+
+        Some(match name {
+            "type" => MenuProperty::Type(match string.as_deref() {
+                Some("separator") => MenuItemType::Separator,
+                _ => MenuItemType::Standard,
+            }),
+            "label" => MenuProperty::Label(string.unwrap_or_default()),
+            "enabled" => MenuProperty::Enabled(boolean.unwrap_or(true)),
+            "visible" => MenuProperty::Visible(boolean.unwrap_or(true)),
+            "icon-name" => MenuProperty::IconName(string.filter(|name| !name.is_empty())),
+            "icon-data" => MenuProperty::IconData(
+                value
+                    .and_then(|value| cast::<Vec<u8>>(value).cloned())
+                    .filter(|data| !data.is_empty()),
+            ),
+            "shortcut" => MenuProperty::Shortcut(
+                value
+                    .and_then(|value| value.as_iter())
+                    .map(|shortcuts| {
+                        shortcuts
+                            .filter_map(|keys| {
+                                Some(
+                                    keys.as_iter()?
+                                        .filter_map(|key| key.as_str().map(str::to_string))
+                                        .collect(),
+                                )
+                            })
+                            .collect()
+                    })
+                    .unwrap_or_default(),
+            ),
+            "toggle-type" => MenuProperty::ToggleType(match string.as_deref() {
+                Some("checkmark") => ToggleType::Checkmark,
+                Some("radio") => ToggleType::Radio,
+                _ => ToggleType::None,
+            }),
+            "toggle-state" => {
+                MenuProperty::ToggleState(match value.and_then(|value| value.as_i64()) {
+                    Some(0) => ToggleState::Off,
+                    Some(1) => ToggleState::On,
+                    _ => ToggleState::Indeterminate,
+                })
+            }
+            "children-display" => {
+                MenuProperty::ChildrenDisplay(string.filter(|display| !display.is_empty()))
+            }
+            _ => return None,
+        })
+    }
+}
+
+/// Converts an ItemsPropertiesUpdated signal into the property changes of each item.
+/// Removed properties are reset to their default value.
+pub fn property_updates(
+    signal: ComCanonicalDbusmenuItemsPropertiesUpdated,
+) -> Vec<(i32, Vec<MenuProperty>)> {
+    let updated = signal.updated_props.into_iter().map(|(id, properties)| {
+        let properties = properties
+            .iter()
+            .filter_map(|(name, value)| MenuProperty::parse(name, Some(&*value.0)))
+            .collect();
+        (id, properties)
+    });
+
+    let removed = signal.removed_props.into_iter().map(|(id, names)| {
+        let properties = names
+            .iter()
+            .filter_map(|name| MenuProperty::parse(name, None))
+            .collect();
+        (id, properties)
+    });
+
+    updated.chain(removed).collect()
+}
+
+/// The `(ia{sv}av)` layout of a menu item as it is sent over the bus
+#[derive(Debug)]
+pub struct Layout {
+    pub id: i32,
+    pub properties: PropMap,
+    pub children: Vec<Layout>,
+}
+
+impl Arg for Layout {
+    const ARG_TYPE: ArgType = ArgType::Struct;
+
+    fn signature() -> Signature<'static> {
+        Signature::from("(ia{sv}av)")
+    }
+}
+
+impl<'a> Get<'a> for Layout {
+    fn get(i: &mut Iter<'a>) -> Option<Self> {
+        let mut fields = i.recurse(ArgType::Struct)?;
+        let id: i32 = fields.read().ok()?;
+        let properties: PropMap = fields.read().ok()?;
+
+        // Every child is a variant that holds another layout
+        let mut children = Vec::new();
+        let mut variants = fields.recurse(ArgType::Array)?;
+        while variants.arg_type() == ArgType::Variant {
+            children.push(Layout::get(&mut variants.recurse(ArgType::Variant)?)?);
+            variants.next();
+        }
+
+        Some(Layout {
+            id,
+            properties,
+            children,
+        })
+    }
+}
+
+pub struct DBusMenu<'conn> {
+    menu: Proxy<'conn, &'conn Connection>,
+}
+
+impl<'conn> DBusMenu<'conn> {
+    pub fn new<D: Into<BusName<'conn>>, P: Into<Path<'conn>>>(
+        destination: D,
+        path: P,
+        connection: &'conn Connection,
+    ) -> Self {
+        DBusMenu {
+            menu: connection.with_proxy(destination, path, TIMEOUT),
+        }
+    }
+
+    /// Fetches the layout below `parent_id` (0 is the root), `recursion_depth` levels deep
+    /// (-1 for everything). Returns the revision of the layout and the parent item.
+    pub fn get_layout(
+        &self,
+        parent_id: i32,
+        recursion_depth: i32,
+    ) -> Result<(u32, MenuItem), TrayError> {
+        let property_names: Vec<&str> = Vec::new();
+        let (revision, layout): (u32, Layout) = error::method_call(
+            &self.menu,
+            INTERFACE,
+            "GetLayout",
+            (parent_id, recursion_depth, property_names),
+        )?;
+
+        Ok((revision, MenuItem::from_layout(layout)))
+    }
+
+    /// Fetches the whole menu
+    pub fn get_root(&self) -> Result<(u32, MenuItem), TrayError> {
+        self.get_layout(0, -1)
+    }
+
+    /// Tells the app that something happened to an item, e.g. that it was `clicked`
+    pub fn event(&self, id: i32, event_id: &str, timestamp: u32) -> Result<(), TrayError> {
+        let data: Box<dyn RefArg> = Box::new(0i32);
+        error::method_call(
+            &self.menu,
+            INTERFACE,
+            "Event",
+            (id, event_id, Variant(data), timestamp),
+        )
+    }
+
+    /// Lets the app update the children of `id` before they are shown. Returns whether the
+    /// layout below `id` has to be fetched again.
+    pub fn about_to_show(&self, id: i32) -> Result<bool, TrayError> {
+        let (needs_update,): (bool,) =
+            error::method_call(&self.menu, INTERFACE, "AboutToShow", (id,))?;
+        Ok(needs_update)
+    }
+
+    pub fn signal<S: ReadAll + SignalArgs, F: 'static + SignalFunction<S>>(
+        &self,
+        f: F,
+    ) -> Result<Token, TrayError> {
+        // Adding the match rule blocks until the bus daemon answers
+        let token = self.menu.match_signal(f);
+        dbus_source::dispatch_queued(self.menu.connection);
+
+        Ok(token?)
+    }
+
+    /// Sent when the children of `parent` changed. Items up to `revision` are affected.
+    pub fn layout_updated<F: 'static + SignalFunction<ComCanonicalDbusmenuLayoutUpdated>>(
+        &self,
+        f: F,
+    ) -> Result<Token, TrayError> {
+        self.signal(f)
+    }
+
+    pub fn items_properties_updated<
+        F: 'static + SignalFunction<ComCanonicalDbusmenuItemsPropertiesUpdated>,
+    >(
+        &self,
+        f: F,
+    ) -> Result<Token, TrayError> {
+        self.signal(f)
+    }
+}
+
+impl<'conn> Clone for DBusMenu<'conn> {
+    fn clone(&self) -> Self {
+        DBusMenu {
+            menu: self.menu.clone(),
+        }
+    }
+}
+
+impl<'conn> fmt::Debug for DBusMenu<'conn> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{} {}", self.menu.destination, self.menu.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dbus::Message;
+
+    type RawLayout = (i32, PropMap, Vec<Variant<Box<dyn RefArg>>>);
+
+    fn layout(
+        id: i32,
+        properties: Vec<(&str, Box<dyn RefArg>)>,
+        children: Vec<RawLayout>,
+    ) -> RawLayout {
+        let properties = properties
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), Variant(value)))
+            .collect();
+        let children = children
+            .into_iter()
+            .map(|child| Variant(Box::new(child) as Box<dyn RefArg>))
+            .collect();
+
+        (id, properties, children)
+    }
+
+    /// Sends `layout` through a message, the way GetLayout returns it
+    fn read_layout(layout: RawLayout) -> MenuItem {
+        let message = Message::new_method_call(":1.1", "/MenuBar", INTERFACE, "GetLayout")
+            .unwrap()
+            .append1(layout);
+
+        MenuItem::from_layout(message.read1::<Layout>().unwrap())
+    }
+
+    #[test]
+    fn reads_nested_layouts() {
+        let root = read_layout(layout(
+            0,
+            vec![("children-display", Box::new("submenu".to_string()))],
+            vec![
+                layout(1, vec![("label", Box::new("_Open".to_string()))], vec![]),
+                layout(2, vec![("type", Box::new("separator".to_string()))], vec![]),
+                layout(
+                    3,
+                    vec![("label", Box::new("Recent".to_string()))],
+                    vec![layout(
+                        4,
+                        vec![("label", Box::new("a.txt".to_string()))],
+                        vec![],
+                    )],
+                ),
+            ],
+        ));
+
+        assert_eq!(root.id, 0);
+        assert!(root.is_submenu());
+        assert_eq!(
+            root.children
+                .iter()
+                .map(|child| child.id)
+                .collect::<Vec<_>>(),
+            vec![1, 2, 3]
+        );
+        assert_eq!(root.children[0].label, "_Open");
+        assert_eq!(root.children[1].item_type, MenuItemType::Separator);
+        assert!(root.children[2].is_submenu());
+        assert_eq!(root.find(4).map(|item| item.label.as_str()), Some("a.txt"));
+    }
+
+    #[test]
+    fn defaults_missing_and_mistyped_properties() {
+        let item = read_layout(layout(
+            1,
+            vec![
+                ("enabled", Box::new(false)),
+                ("visible", Box::new("no".to_string())),
+                ("label", Box::new(42i32)),
+                ("unknown", Box::new(1u8)),
+            ],
+            vec![],
+        ));
+
+        assert!(!item.enabled);
+        assert!(item.visible);
+        assert_eq!(item.label, "");
+        assert_eq!(item.item_type, MenuItemType::Standard);
+        assert_eq!(item.toggle_type, ToggleType::None);
+        assert_eq!(item.toggle_state, ToggleState::Indeterminate);
+        assert!(!item.is_submenu());
+    }
+
+    #[test]
+    fn reads_toggle_states() {
+        let toggle_state = |state: i32| {
+            read_layout(layout(
+                1,
+                vec![
+                    ("toggle-type", Box::new("checkmark".to_string())),
+                    ("toggle-state", Box::new(state)),
+                ],
+                vec![],
+            ))
+        };
+
+        assert_eq!(toggle_state(0).toggle_type, ToggleType::Checkmark);
+        assert_eq!(toggle_state(-1).toggle_state, ToggleState::Indeterminate);
+        assert_eq!(toggle_state(0).toggle_state, ToggleState::Off);
+        assert_eq!(toggle_state(1).toggle_state, ToggleState::On);
+    }
+
+    #[test]
+    fn reads_icon_data_and_shortcuts() {
+        let shortcut = vec![
+            vec!["Control".to_string(), "q".to_string()],
+            vec!["Alt".to_string(), "F4".to_string()],
+        ];
+        let item = read_layout(layout(
+            1,
+            vec![
+                ("icon-data", Box::new(vec![0x89u8, b'P', b'N', b'G'])),
+                ("shortcut", Box::new(shortcut.clone())),
+            ],
+            vec![],
+        ));
+
+        assert_eq!(item.icon_data, Some(vec![0x89, b'P', b'N', b'G']));
+        assert_eq!(item.shortcut, shortcut);
+
+        let item = read_layout(layout(
+            1,
+            vec![("icon-data", Box::new(Vec::<u8>::new()))],
+            vec![],
+        ));
+        assert_eq!(item.icon_data, None);
+    }
+
+    #[test]
+    fn resets_removed_properties() {
+        let updated: PropMap = vec![(
+            "label".to_string(),
+            Variant(Box::new("Quit".to_string()) as Box<dyn RefArg>),
+        )]
+        .into_iter()
+        .collect();
+        let signal = ComCanonicalDbusmenuItemsPropertiesUpdated {
+            updated_props: vec![(1, updated)],
+            removed_props: vec![(
+                2,
+                vec![
+                    "label".to_string(),
+                    "enabled".to_string(),
+                    "toggle-state".to_string(),
+                    "unknown".to_string(),
+                ],
+            )],
+        };
+
+        assert_eq!(
+            property_updates(signal),
+            vec![
+                (1, vec![MenuProperty::Label("Quit".to_string())]),
+                (
+                    2,
+                    vec![
+                        MenuProperty::Label(String::new()),
+                        MenuProperty::Enabled(true),
+                        MenuProperty::ToggleState(ToggleState::Indeterminate),
+                    ]
+                ),
+            ]
+        );
+    }
+}
@@ -0,0 +1,109 @@
+/**
+    tray (c) dotHQ 2021
+    A standalone tray application
+
+    Bridges a blocking dbus Connection into the GLib main loop. The file descriptor of the
+    connection is watched from the default main context, so match callbacks registered on
+    the connection run on the GTK thread whenever a message arrives. Messages that libdbus
+    reads while waiting for the reply to a blocking call never wake up the file descriptor,
+    so they are dispatched from an idle source after each such call.
+
+    This Source Code Form is subject to the terms of the Mozilla Public
+    License, v. 2.0. If a copy of the MPL was not distributed with this
+    file, You can obtain one at http://mozilla.org/MPL/2.0/.
+*/
+use std::{cell::Cell, error::Error, ptr, time::Duration};
+
+use dbus::{
+    blocking::Connection,
+    channel::{BusType, Channel},
+};
+use glib::{Continue, IOCondition, MainContext, SourceId};
+
+thread_local! {
+    // The connection that is attached to the main context of this thread
+    static ATTACHED: Cell<Option<&'static Connection>> = const { Cell::new(None) };
+    // Whether dispatching the queue of that connection is already scheduled
+    static DISPATCH_SCHEDULED: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Opens a new session bus connection that is able to be attached to the main loop
+pub fn new_session() -> Result<Connection, Box<dyn Error>> {
+    let mut channel = Channel::get_private(BusType::Session)?;
+    channel.set_watch_enabled(true);
+
+    Ok(Connection::from(channel))
+}
+
+/// Dispatches incoming messages on `connection` from the default main context. The
+/// connection has to be created with `new_session`, and this has to be called from the
+/// GTK thread.
+pub fn attach(connection: &'static Connection) -> SourceId {
+    let fd = connection.channel().watch().fd;
+
+    // Adding a local source requires this thread to own the main context
+    let context = MainContext::default();
+    let acquired = context.acquire();
+
+    let fd_source = glib::unix_fd_add_local(
+        fd,
+        IOCondition::IN | IOCondition::HUP | IOCondition::ERR,
+        move |_, condition| {
+            if condition.intersects(IOCondition::HUP | IOCondition::ERR) {
+                eprintln!("Lost the connection to the session bus");
+                return Continue(false);
+            }
+
+            dispatch_pending(connection);
+            Continue(true)
+        },
+    );
+
+    if acquired {
+        context.release();
+    }
+
+    // Messages may have been queued by the calls that were made before attaching
+    ATTACHED.with(|attached| attached.set(Some(connection)));
+    dispatch_queued(connection);
+
+    fd_source
+}
+
+/// Schedules dispatching the messages that were queued during a blocking call on
+/// `connection`. Unlike `dispatch_pending`, this is safe to call from match callbacks.
+/// Connections that are not attached to this thread are left alone.
+pub fn dispatch_queued(connection: &Connection) {
+    let attached = match ATTACHED.with(Cell::get) {
+        Some(attached) if ptr::eq(attached, connection) => attached,
+        _ => return,
+    };
+
+    if DISPATCH_SCHEDULED.with(|scheduled| scheduled.replace(true)) {
+        return;
+    }
+
+    glib::idle_add_local(move || {
+        DISPATCH_SCHEDULED.with(|scheduled| scheduled.set(false));
+        dispatch_pending(attached);
+        Continue(false)
+    });
+}
+
+/// Dispatches every message that is currently available on `connection` without blocking.
+/// Must not be called from within a match callback of the same connection.
+pub fn dispatch_pending(connection: &Connection) {
+    loop {
+        match connection.process(Duration::from_millis(0)) {
+            Ok(true) => continue,
+            Ok(false) => break,
+            Err(error) => {
+                eprintln!("Failed to process D-Bus messages: {}", error);
+                break;
+            }
+        }
+    }
+
+    // Make sure that messages sent from callbacks actually leave
+    connection.channel().flush();
+}
@@ -0,0 +1,157 @@
+/**
+    tray (c) dotHQ 2021
+    A standalone tray application
+
+    Errors of the host API. D-Bus errors are sorted by what they mean for the tray, so an
+    item that went away can be told apart from one that leaves out an optional property.
+
+    This Source Code Form is subject to the terms of the Mozilla Public
+    License, v. 2.0. If a copy of the MPL was not distributed with this
+    file, You can obtain one at http://mozilla.org/MPL/2.0/.
+*/
+use std::{error::Error, fmt};
+
+use dbus::{
+    arg::{AppendAll, Get, IterAppend, ReadAll, TypeMismatchError, Variant},
+    blocking::{BlockingSender, Connection, Proxy},
+    strings::{Interface, Member},
+    Message,
+};
+
+use crate::dbus_source;
+
+#[derive(Debug)]
+pub enum TrayError {
+    /// The other end is not on the bus (anymore)
+    ServiceUnknown(dbus::Error),
+    /// The other end is on the bus, but has no object at the path
+    UnknownObject(dbus::Error),
+    /// The object does not have the property
+    UnknownProperty(dbus::Error),
+    /// The object does not implement the method
+    UnknownMethod(dbus::Error),
+    /// No reply arrived in time
+    Timeout(dbus::Error),
+    /// A value on the bus has a different type than the spec says
+    TypeMismatch(String),
+    /// A `busname/objectpath` string that does not name an item
+    InvalidRegistration(String),
+    /// Any other D-Bus error
+    DBus(dbus::Error),
+}
+
+impl fmt::Display for TrayError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TrayError::ServiceUnknown(error) => write!(fmt, "Service unknown: {}", message(error)),
+            TrayError::UnknownObject(error) => write!(fmt, "Unknown object: {}", message(error)),
+            TrayError::UnknownProperty(error) => {
+                write!(fmt, "Unknown property: {}", message(error))
+            }
+            TrayError::UnknownMethod(error) => write!(fmt, "Unknown method: {}", message(error)),
+            TrayError::Timeout(error) => write!(fmt, "Timed out: {}", message(error)),
+            TrayError::TypeMismatch(mismatch) => write!(fmt, "{}", mismatch),
+            TrayError::InvalidRegistration(registration) => {
+                write!(fmt, "Invalid status notifier item {:?}", registration)
+            }
+            TrayError::DBus(error) => write!(fmt, "{}", error),
+        }
+    }
+}
+
+impl Error for TrayError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            TrayError::ServiceUnknown(error)
+            | TrayError::UnknownObject(error)
+            | TrayError::UnknownProperty(error)
+            | TrayError::UnknownMethod(error)
+            | TrayError::Timeout(error)
+            | TrayError::DBus(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<dbus::Error> for TrayError {
+    fn from(error: dbus::Error) -> Self {
+        match error.name().unwrap_or_default() {
+            "org.freedesktop.DBus.Error.ServiceUnknown"
+            | "org.freedesktop.DBus.Error.NameHasNoOwner" => TrayError::ServiceUnknown(error),
+            "org.freedesktop.DBus.Error.UnknownObject" => TrayError::UnknownObject(error),
+            "org.freedesktop.DBus.Error.UnknownProperty" => TrayError::UnknownProperty(error),
+            "org.freedesktop.DBus.Error.UnknownMethod"
+            | "org.freedesktop.DBus.Error.UnknownInterface"
+            | "org.freedesktop.DBus.Error.NotSupported" => TrayError::UnknownMethod(error),
+            "org.freedesktop.DBus.Error.NoReply"
+            | "org.freedesktop.DBus.Error.Timeout"
+            | "org.freedesktop.DBus.Error.TimedOut" => TrayError::Timeout(error),
+            "org.freedesktop.DBus.Error.InvalidSignature" => {
+                TrayError::TypeMismatch(message(&error).to_string())
+            }
+            _ => TrayError::DBus(error),
+        }
+    }
+}
+
+impl From<TypeMismatchError> for TrayError {
+    fn from(error: TypeMismatchError) -> Self {
+        TrayError::TypeMismatch(error.to_string())
+    }
+}
+
+fn message(error: &dbus::Error) -> &str {
+    error.message().unwrap_or_default()
+}
+
+/// Calls a method. Unlike `Proxy::method_call`, a reply of the wrong type is reported as
+/// a type mismatch, instead of as a generic failure. Messages that were queued while
+/// waiting for the reply are dispatched afterwards, as are those of the other blocking
+/// calls of the host (adding and removing match rules).
+pub fn method_call<R: ReadAll, A: AppendAll>(
+    proxy: &Proxy<&Connection>,
+    interface: &str,
+    method: &str,
+    args: A,
+) -> Result<R, TrayError> {
+    let mut message = Message::method_call(
+        &proxy.destination,
+        &proxy.path,
+        &Interface::from(interface),
+        &Member::from(method),
+    );
+    args.append(&mut IterAppend::new(&mut message));
+
+    let reply = proxy
+        .connection
+        .send_with_reply_and_block(message, proxy.timeout);
+    // Signals that arrived while waiting for the reply are not dispatched otherwise
+    dbus_source::dispatch_queued(proxy.connection);
+
+    Ok(R::read(&mut reply?.iter_init())?)
+}
+
+/// Reads a property. GLib and Qt answer InvalidArgs for properties that they do not have,
+/// so that is an unknown property as well.
+pub fn get_property<R: for<'b> Get<'b> + 'static>(
+    proxy: &Proxy<&Connection>,
+    interface: &str,
+    name: &str,
+) -> Result<R, TrayError> {
+    let result: Result<(Variant<R>,), TrayError> = method_call(
+        proxy,
+        "org.freedesktop.DBus.Properties",
+        "Get",
+        (interface, name),
+    );
+
+    match result {
+        Ok((value,)) => Ok(value.0),
+        Err(TrayError::DBus(error))
+            if error.name() == Some("org.freedesktop.DBus.Error.InvalidArgs") =>
+        {
+            Err(TrayError::UnknownProperty(error))
+        }
+        Err(error) => Err(error),
+    }
+}
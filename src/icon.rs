@@ -0,0 +1,212 @@
+/**
+    tray (c) dotHQ 2021
+    A standalone tray application
+
+    Finds the icon of a status notifier item. Apps are inconsistent about where they put
+    their icon, so every place an icon may be is tried in turn, down to a generic icon, and
+    an item is never shown as an empty button. Overlay icons are drawn over the icon here
+    as well.
+
+    This Source Code Form is subject to the terms of the Mozilla Public
+    License, v. 2.0. If a copy of the MPL was not distributed with this
+    file, You can obtain one at http://mozilla.org/MPL/2.0/.
+*/
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use gdk_pixbuf::{prelude::*, InterpType, Pixbuf, PixbufAnimation};
+use gio::{prelude::*, DesktopAppInfo};
+use gtk::{prelude::*, IconLookupFlags, IconTheme};
+
+use crate::pixmap::{self, IconPixmap};
+
+const FALLBACK_ICON_NAME: &str = "application-x-executable";
+
+/// The places an icon is looked for, in the order they are tried
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IconSource {
+    Path,        // IconName is an absolute path to an image
+    ThemePath,   // IconName found in the IconThemePath of the item
+    IconTheme,   // IconName found in the icon theme of the user
+    Pixmap,      // The pixmap property
+    DesktopFile, // The icon of the desktop file that matches the Id of the item
+    Fallback,    // A generic icon
+}
+
+impl fmt::Display for IconSource {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let source = match self {
+            IconSource::Path => "icon path",
+            IconSource::ThemePath => "icon theme path",
+            IconSource::IconTheme => "icon theme",
+            IconSource::Pixmap => "pixmap",
+            IconSource::DesktopFile => "desktop file",
+            IconSource::Fallback => "fallback icon",
+        };
+
+        write!(fmt, "{}", source)
+    }
+}
+
+/// Finds an icon of `size` pixels for the item with the given `id`, along with where it was
+/// found. `icon_theme` is the theme of items that have an IconThemePath.
+pub fn resolve(
+    id: &str,
+    icon_name: &str,
+    pixmaps: &[IconPixmap],
+    icon_theme: Option<&IconTheme>,
+    size: i32,
+) -> Option<(Pixbuf, IconSource)> {
+    let default_theme = IconTheme::get_default();
+
+    find(icon_name, pixmaps, icon_theme, size)
+        .or_else(|| {
+            default_theme
+                .as_ref()
+                .and_then(|default_theme| load_desktop_file_icon(default_theme, id, size))
+                .map(|pixbuf| (pixbuf, IconSource::DesktopFile))
+        })
+        .or_else(|| {
+            default_theme
+                .as_ref()
+                .and_then(|default_theme| load_theme_icon(default_theme, FALLBACK_ICON_NAME, size))
+                .map(|pixbuf| (pixbuf, IconSource::Fallback))
+        })
+}
+
+/// Draws the overlay icon of an item over the lower-right quarter of its icon
+pub fn composite_overlay(
+    base: &Pixbuf,
+    icon_name: &str,
+    pixmaps: &[IconPixmap],
+    icon_theme: Option<&IconTheme>,
+) -> Option<Pixbuf> {
+    let size = (base.get_width().max(base.get_height()) / 2).max(1);
+    let (overlay, _) = find(icon_name, pixmaps, icon_theme, size)?;
+
+    // Pixmaps keep their aspect ratio, so the overlay is not necessarily square
+    let width = overlay.get_width().min(base.get_width());
+    let height = overlay.get_height().min(base.get_height());
+    let (x, y) = (base.get_width() - width, base.get_height() - height);
+
+    let composited = base.copy()?;
+    overlay.composite(
+        &composited,
+        x,
+        y,
+        width,
+        height,
+        x as f64,
+        y as f64,
+        1.0,
+        1.0,
+        InterpType::Bilinear,
+        255,
+    );
+
+    Some(composited)
+}
+
+/// Loads the AttentionMovieName of an item, which is either the path of an animated image
+/// or the name of one in the icon theme. `size` only picks the image from the theme, the
+/// frames still have to be scaled.
+pub fn load_animation(
+    movie_name: &str,
+    icon_theme: Option<&IconTheme>,
+    size: i32,
+) -> Option<PixbufAnimation> {
+    if movie_name.is_empty() {
+        return None;
+    }
+
+    let path = if Path::new(movie_name).is_absolute() {
+        PathBuf::from(movie_name)
+    } else {
+        let default_theme = IconTheme::get_default();
+        icon_theme
+            .into_iter()
+            .chain(default_theme.as_ref())
+            .find_map(|icon_theme| {
+                icon_theme
+                    .lookup_icon(movie_name, size, IconLookupFlags::empty())?
+                    .get_filename()
+            })?
+    };
+
+    match PixbufAnimation::from_file(&path) {
+        Ok(animation) if !animation.is_static_image() => Some(animation),
+        // A still image is no better than the attention icon
+        Ok(_) => None,
+        Err(error) => {
+            eprintln!("Failed to load animation {}: {}", movie_name, error);
+            None
+        }
+    }
+}
+
+/// Looks for the icon of an item in the places the app can put it
+fn find(
+    icon_name: &str,
+    pixmaps: &[IconPixmap],
+    icon_theme: Option<&IconTheme>,
+    size: i32,
+) -> Option<(Pixbuf, IconSource)> {
+    let default_theme = IconTheme::get_default();
+    let from_theme = |icon_theme: Option<&IconTheme>| {
+        icon_theme.and_then(|icon_theme| load_theme_icon(icon_theme, icon_name, size))
+    };
+
+    load_path_icon(icon_name, size)
+        .map(|pixbuf| (pixbuf, IconSource::Path))
+        .or_else(|| from_theme(icon_theme).map(|pixbuf| (pixbuf, IconSource::ThemePath)))
+        .or_else(|| {
+            from_theme(default_theme.as_ref()).map(|pixbuf| (pixbuf, IconSource::IconTheme))
+        })
+        .or_else(|| {
+            pixmap::pixbuf_for_size(pixmaps, size).map(|pixbuf| (pixbuf, IconSource::Pixmap))
+        })
+}
+
+/// Some apps put the path of an image into IconName
+fn load_path_icon(icon_name: &str, size: i32) -> Option<Pixbuf> {
+    if !Path::new(icon_name).is_absolute() {
+        return None;
+    }
+
+    match Pixbuf::from_file_at_scale(icon_name, size, size, true) {
+        Ok(pixbuf) => Some(pixbuf),
+        Err(error) => {
+            eprintln!("Failed to load icon {}: {}", icon_name, error);
+            None
+        }
+    }
+}
+
+fn load_theme_icon(icon_theme: &IconTheme, icon_name: &str, size: i32) -> Option<Pixbuf> {
+    if icon_name.is_empty() {
+        return None;
+    }
+
+    icon_theme
+        .lookup_icon(icon_name, size, IconLookupFlags::FORCE_SIZE)?
+        .load_icon()
+        .ok()
+}
+
+/// The Id of most items is the name of their app, which is usually also the name of its
+/// desktop file
+fn load_desktop_file_icon(icon_theme: &IconTheme, id: &str, size: i32) -> Option<Pixbuf> {
+    if id.is_empty() {
+        return None;
+    }
+
+    let app_info = DesktopAppInfo::new(&format!("{}.desktop", id))
+        .or_else(|| DesktopAppInfo::new(&format!("{}.desktop", id.to_lowercase())))?;
+
+    icon_theme
+        .lookup_by_gicon(&app_info.get_icon()?, size, IconLookupFlags::FORCE_SIZE)?
+        .load_icon()
+        .ok()
+}
@@ -0,0 +1,60 @@
+// The signals of com.canonical.dbusmenu that the menu client listens to, written by hand in
+// the shape of `dbus-codegen-rust` output, see https://github.com/diwic/dbus-rs. Methods are
+// called through `error::method_call` instead.
+use dbus as dbus;
+#[allow(unused_imports)]
+use dbus::arg;
+
+#[derive(Debug)]
+pub struct ComCanonicalDbusmenuItemsPropertiesUpdated {
+    pub updated_props: Vec<(i32, arg::PropMap)>,
+    pub removed_props: Vec<(i32, Vec<String>)>,
+}
+
+impl arg::AppendAll for ComCanonicalDbusmenuItemsPropertiesUpdated {
+    fn append(&self, i: &mut arg::IterAppend) {
+        arg::RefArg::append(&self.updated_props, i);
+        arg::RefArg::append(&self.removed_props, i);
+    }
+}
+
+impl arg::ReadAll for ComCanonicalDbusmenuItemsPropertiesUpdated {
+    fn read(i: &mut arg::Iter) -> Result<Self, arg::TypeMismatchError> {
+        Ok(ComCanonicalDbusmenuItemsPropertiesUpdated {
+            updated_props: i.read()?,
+            removed_props: i.read()?,
+        })
+    }
+}
+
+impl dbus::message::SignalArgs for ComCanonicalDbusmenuItemsPropertiesUpdated {
+    const NAME: &'static str = "ItemsPropertiesUpdated";
+    const INTERFACE: &'static str = "com.canonical.dbusmenu";
+}
+
+#[derive(Debug)]
+pub struct ComCanonicalDbusmenuLayoutUpdated {
+    pub revision: u32,
+    pub parent: i32,
+}
+
+impl arg::AppendAll for ComCanonicalDbusmenuLayoutUpdated {
+    fn append(&self, i: &mut arg::IterAppend) {
+        arg::RefArg::append(&self.revision, i);
+        arg::RefArg::append(&self.parent, i);
+    }
+}
+
+impl arg::ReadAll for ComCanonicalDbusmenuLayoutUpdated {
+    fn read(i: &mut arg::Iter) -> Result<Self, arg::TypeMismatchError> {
+        Ok(ComCanonicalDbusmenuLayoutUpdated {
+            revision: i.read()?,
+            parent: i.read()?,
+        })
+    }
+}
+
+impl dbus::message::SignalArgs for ComCanonicalDbusmenuLayoutUpdated {
+    const NAME: &'static str = "LayoutUpdated";
+    const INTERFACE: &'static str = "com.canonical.dbusmenu";
+}
@@ -0,0 +1,34 @@
+// Signals trimmed from `dbus-codegen-rust -d org.freedesktop.DBus -p /org/freedesktop/DBus -m None`, see https://github.com/diwic/dbus-rs
+use dbus as dbus;
+#[allow(unused_imports)]
+use dbus::arg;
+
+#[derive(Debug)]
+pub struct OrgFreedesktopDBusNameOwnerChanged {
+    pub arg0: String,
+    pub arg1: String,
+    pub arg2: String,
+}
+
+impl arg::AppendAll for OrgFreedesktopDBusNameOwnerChanged {
+    fn append(&self, i: &mut arg::IterAppend) {
+        arg::RefArg::append(&self.arg0, i);
+        arg::RefArg::append(&self.arg1, i);
+        arg::RefArg::append(&self.arg2, i);
+    }
+}
+
+impl arg::ReadAll for OrgFreedesktopDBusNameOwnerChanged {
+    fn read(i: &mut arg::Iter) -> Result<Self, arg::TypeMismatchError> {
+        Ok(OrgFreedesktopDBusNameOwnerChanged {
+            arg0: i.read()?,
+            arg1: i.read()?,
+            arg2: i.read()?,
+        })
+    }
+}
+
+impl dbus::message::SignalArgs for OrgFreedesktopDBusNameOwnerChanged {
+    const NAME: &'static str = "NameOwnerChanged";
+    const INTERFACE: &'static str = "org.freedesktop.DBus";
+}
@@ -1,2 +1,5 @@
+pub mod dbus_menu;
+pub mod freedesktop_dbus;
 pub mod status_notifier_item;
+pub mod status_notifier_item_ext;
 pub mod status_notifier_watcher;
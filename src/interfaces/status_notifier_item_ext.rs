@@ -0,0 +1,48 @@
+// Signals of org.kde.StatusNotifierItem that are missing from status_notifier_item.rs,
+// because the item it was generated from does not declare them. Written by hand in the
+// same shape as the generated signals.
+use dbus as dbus;
+#[allow(unused_imports)]
+use dbus::arg;
+
+#[derive(Debug)]
+pub struct OrgKdeStatusNotifierItemNewOverlayIcon {
+}
+
+impl arg::AppendAll for OrgKdeStatusNotifierItemNewOverlayIcon {
+    fn append(&self, _: &mut arg::IterAppend) {
+    }
+}
+
+impl arg::ReadAll for OrgKdeStatusNotifierItemNewOverlayIcon {
+    fn read(_: &mut arg::Iter) -> Result<Self, arg::TypeMismatchError> {
+        Ok(OrgKdeStatusNotifierItemNewOverlayIcon {
+        })
+    }
+}
+
+impl dbus::message::SignalArgs for OrgKdeStatusNotifierItemNewOverlayIcon {
+    const NAME: &'static str = "NewOverlayIcon";
+    const INTERFACE: &'static str = "org.kde.StatusNotifierItem";
+}
+
+#[derive(Debug)]
+pub struct OrgKdeStatusNotifierItemNewToolTip {
+}
+
+impl arg::AppendAll for OrgKdeStatusNotifierItemNewToolTip {
+    fn append(&self, _: &mut arg::IterAppend) {
+    }
+}
+
+impl arg::ReadAll for OrgKdeStatusNotifierItemNewToolTip {
+    fn read(_: &mut arg::Iter) -> Result<Self, arg::TypeMismatchError> {
+        Ok(OrgKdeStatusNotifierItemNewToolTip {
+        })
+    }
+}
+
+impl dbus::message::SignalArgs for OrgKdeStatusNotifierItemNewToolTip {
+    const NAME: &'static str = "NewToolTip";
+    const INTERFACE: &'static str = "org.kde.StatusNotifierItem";
+}
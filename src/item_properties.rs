@@ -0,0 +1,273 @@
+/**
+    tray (c) dotHQ 2021
+    A standalone tray application
+
+    A snapshot of all properties of a status notifier item, read with a single GetAll. Apps
+    leave out plenty of properties, so each of them is optional, and a property with the
+    wrong type is treated as if it was left out instead of failing the whole item.
+
+    This Source Code Form is subject to the terms of the Mozilla Public
+    License, v. 2.0. If a copy of the MPL was not distributed with this
+    file, You can obtain one at http://mozilla.org/MPL/2.0/.
+*/
+use std::{convert::Infallible, fmt, str::FromStr};
+
+use dbus::{
+    arg::{Arg, ArgType, Get, Iter},
+    strings::{Path, Signature},
+};
+
+use crate::{
+    pixmap::{IconPixmap, RawPixmap},
+    tool_tip::{RawToolTip, ToolTip},
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Category {
+    ApplicationStatus,
+    Communications,
+    SystemServices,
+    Hardware,
+    Unknown(String), // Not in the spec, kept as sent
+}
+
+impl FromStr for Category {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "ApplicationStatus" => Category::ApplicationStatus,
+            "Communications" => Category::Communications,
+            "SystemServices" => Category::SystemServices,
+            "Hardware" => Category::Hardware,
+            _ => Category::Unknown(s.to_string()),
+        })
+    }
+}
+
+impl fmt::Display for Category {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let category = match self {
+            Category::ApplicationStatus => "ApplicationStatus",
+            Category::Communications => "Communications",
+            Category::SystemServices => "SystemServices",
+            Category::Hardware => "Hardware",
+            Category::Unknown(category) => category,
+        };
+
+        write!(fmt, "{}", category)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Status {
+    Passive,
+    Active,
+    NeedsAttention,
+    Unknown(String), // Not in the spec, kept as sent
+}
+
+impl Status {
+    /// Passive items are hidden. A status we do not know is shown, so the item is not lost.
+    pub fn is_visible(&self) -> bool {
+        *self != Status::Passive
+    }
+}
+
+impl FromStr for Status {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "Passive" => Status::Passive,
+            "Active" => Status::Active,
+            "NeedsAttention" => Status::NeedsAttention,
+            _ => Status::Unknown(s.to_string()),
+        })
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let status = match self {
+            Status::Passive => "Passive",
+            Status::Active => "Active",
+            Status::NeedsAttention => "NeedsAttention",
+            Status::Unknown(status) => status,
+        };
+
+        write!(fmt, "{}", status)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ItemProperties {
+    pub category: Option<Category>,
+    pub id: Option<String>,
+    pub title: Option<String>,
+    pub status: Option<Status>,
+    pub window_id: Option<u32>,
+    pub icon_theme_path: Option<String>,
+    pub icon_name: Option<String>,
+    pub icon_pixmap: Option<Vec<IconPixmap>>,
+    pub overlay_icon_name: Option<String>,
+    pub overlay_icon_pixmap: Option<Vec<IconPixmap>>,
+    pub attention_icon_name: Option<String>,
+    pub attention_icon_pixmap: Option<Vec<IconPixmap>>,
+    pub attention_movie_name: Option<String>,
+    pub tool_tip: Option<ToolTip>,
+    pub item_is_menu: Option<bool>,
+    pub menu: Option<Path<'static>>,
+}
+
+impl ItemProperties {
+    /// Reads a single property from the variant that holds it. Properties that are not in
+    /// the spec are skipped.
+    fn read_property(&mut self, name: &str, value: &mut Iter) {
+        match name {
+            "Category" => self.category = value.get::<&str>().and_then(|s| s.parse().ok()),
+            "Id" => self.id = value.get(),
+            "Title" => self.title = value.get(),
+            "Status" => self.status = value.get::<&str>().and_then(|s| s.parse().ok()),
+            // The spec says int32, but most apps send the uint32 that X11 uses
+            "WindowId" => {
+                self.window_id = value
+                    .get::<u32>()
+                    .or_else(|| value.get::<i32>().map(|id| id as u32))
+            }
+            "IconThemePath" => self.icon_theme_path = value.get(),
+            "IconName" => self.icon_name = value.get(),
+            "IconPixmap" => {
+                self.icon_pixmap = value.get::<Vec<RawPixmap>>().map(IconPixmap::from_raw)
+            }
+            "OverlayIconName" => self.overlay_icon_name = value.get(),
+            "OverlayIconPixmap" => {
+                self.overlay_icon_pixmap = value.get::<Vec<RawPixmap>>().map(IconPixmap::from_raw)
+            }
+            "AttentionIconName" => self.attention_icon_name = value.get(),
+            "AttentionIconPixmap" => {
+                self.attention_icon_pixmap = value.get::<Vec<RawPixmap>>().map(IconPixmap::from_raw)
+            }
+            "AttentionMovieName" => self.attention_movie_name = value.get(),
+            "ToolTip" => self.tool_tip = value.get::<RawToolTip>().map(ToolTip::from_raw),
+            "ItemIsMenu" => self.item_is_menu = value.get(),
+            "Menu" => self.menu = value.get::<Path>().map(Path::into_static),
+            _ => {}
+        }
+    }
+}
+
+impl Arg for ItemProperties {
+    const ARG_TYPE: ArgType = ArgType::Array;
+
+    fn signature() -> Signature<'static> {
+        Signature::from("a{sv}")
+    }
+}
+
+impl<'a> Get<'a> for ItemProperties {
+    fn get(i: &mut Iter<'a>) -> Option<Self> {
+        let mut properties = ItemProperties::default();
+
+        let mut entries = i.recurse(ArgType::Array)?;
+        while entries.arg_type() == ArgType::DictEntry {
+            let mut entry = entries.recurse(ArgType::DictEntry)?;
+            let name: &str = entry.read().ok()?;
+            properties.read_property(name, &mut entry.recurse(ArgType::Variant)?);
+            entries.next();
+        }
+
+        Some(properties)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dbus::{
+        arg::{PropMap, RefArg, Variant},
+        Message,
+    };
+
+    /// Sends `properties` through a message, the way GetAll returns them
+    fn read_properties(properties: Vec<(&str, Box<dyn RefArg>)>) -> ItemProperties {
+        let properties: PropMap = properties
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), Variant(value)))
+            .collect();
+        let message = Message::new_method_call(
+            ":1.1",
+            "/StatusNotifierItem",
+            "org.freedesktop.DBus.Properties",
+            "GetAll",
+        )
+        .unwrap()
+        .append1(properties);
+
+        message.read1().unwrap()
+    }
+
+    #[test]
+    fn keeps_good_properties_next_to_mistyped_ones() {
+        let properties = read_properties(vec![
+            ("Id", Box::new("nm-applet".to_string())),
+            ("Title", Box::new(42i32)),
+            ("ItemIsMenu", Box::new("yes".to_string())),
+            ("IconPixmap", Box::new(vec![(1i32, 1i32, vec![0u8; 4])])),
+            ("Menu", Box::new(Path::from("/MenuBar"))),
+            ("XAyatanaLabel", Box::new("3".to_string())),
+        ]);
+
+        assert_eq!(properties.id.as_deref(), Some("nm-applet"));
+        assert_eq!(properties.title, None);
+        assert_eq!(properties.item_is_menu, None);
+        assert_eq!(
+            properties.icon_pixmap,
+            Some(vec![IconPixmap::new(1, 1, vec![0; 4]).unwrap()])
+        );
+        assert_eq!(properties.menu, Some(Path::from("/MenuBar")));
+    }
+
+    #[test]
+    fn accepts_both_window_id_types() {
+        let properties = read_properties(vec![("WindowId", Box::new(7i32))]);
+        assert_eq!(properties.window_id, Some(7));
+
+        let properties = read_properties(vec![("WindowId", Box::new(7u32))]);
+        assert_eq!(properties.window_id, Some(7));
+    }
+
+    #[test]
+    fn keeps_unknown_status_and_category() {
+        let properties = read_properties(vec![
+            ("Status", Box::new("Blinking".to_string())),
+            ("Category", Box::new("Games".to_string())),
+        ]);
+
+        assert_eq!(
+            properties.status,
+            Some(Status::Unknown("Blinking".to_string()))
+        );
+        assert_eq!(
+            properties.category,
+            Some(Category::Unknown("Games".to_string()))
+        );
+        assert!(properties.status.unwrap().is_visible());
+    }
+
+    #[test]
+    fn round_trips_status_and_category() {
+        for status in &["Passive", "Active", "NeedsAttention", "Blinking"] {
+            assert_eq!(status.parse::<Status>().unwrap().to_string(), *status);
+        }
+
+        for category in &[
+            "ApplicationStatus",
+            "Communications",
+            "SystemServices",
+            "Hardware",
+        ] {
+            assert_eq!(category.parse::<Category>().unwrap().to_string(), *category);
+        }
+    }
+}
@@ -11,8 +11,8 @@
 // extern crate gio;
 extern crate gtk;
 use std::{
+    collections::HashMap,
     error::Error,
-    path::{Path, PathBuf},
     sync::{Arc, Mutex},
     thread,
     time::Duration,
@@ -20,20 +20,38 @@ use std::{
 
 use dbus::blocking::Connection;
 
-use gtk::{prelude::*, Builder, ButtonBuilder, IconSize, Image};
-use status_notifier_host::StatusNotifierItem;
+use app_indicator::{AppIndicator, Event};
+use error::TrayError;
+use gtk::{prelude::*, Builder};
+use settings::Settings;
 
 // Import glade file to a constant
 const LAYOUT: &str = include_str!("tray.glade");
+// How often loading an item that does not answer in time is tried
+const LOAD_ATTEMPTS: u32 = 4;
+// The wait before the first retry in milliseconds, doubled for every retry after it
+const RETRY_DELAY: u32 = 1000;
 
 // mod interface;
+mod app_indicator;
+mod dbus_menu;
+mod dbus_source;
+mod error;
+mod icon;
 mod interfaces;
+mod item_properties;
+mod menu;
+mod pixmap;
+mod settings;
 mod status_notifier_host;
 mod status_notifier_watcher;
+mod tool_tip;
 
 fn main() -> Result<(), Box<dyn Error>> {
     gtk::init().expect("Failed to initialize GTK");
 
+    let settings = Settings::from_env();
+
     // Create the UI from a glade file
     let builder = Builder::from_string(LAYOUT);
     let window: gtk::Window = builder.get_object("main_window").unwrap();
@@ -59,102 +77,212 @@ fn main() -> Result<(), Box<dyn Error>> {
     thread::sleep(Duration::from_millis(10));
 
     // Create a new connection that is going to be used for the host
-    let host_connection: &'static mut Connection = Box::leak(Box::new(Connection::new_session()?));
+    let host_connection: &'static Connection = Box::leak(Box::new(dbus_source::new_session()?));
 
     // Create the host
-    let host = status_notifier_host::StatusNotifierHost::new(&host_connection)?;
+    let host = status_notifier_host::StatusNotifierHost::new(host_connection)?;
+
+    // Let the watcher know that there is something displaying its items
+    host.register()?;
+
+    // D-Bus signal handlers cannot touch GTK directly, so they post events into the
+    // GTK main loop instead
+    let (sender, receiver) = glib::MainContext::channel(glib::PRIORITY_DEFAULT);
+
+    // Listen for app indicators coming and going. This has to happen before the currently
+    // registered items are read, otherwise items registering in between would be missed
+    let registered_sender = sender.clone();
+    host.status_notifier_item_registered(move |signal, _, _| {
+        let _ = registered_sender.send(Event::ItemRegistered(signal.arg_0));
+        true
+    })?;
+    let unregistered_sender = sender.clone();
+    host.status_notifier_item_unregistered(move |signal, _, _| {
+        let _ = unregistered_sender.send(Event::ItemUnregistered(signal.arg_0));
+        true
+    })?;
 
     // Here is the important stuff. We are going to grab all of the app indicators
-    // from the host and add them to an array
-    let app_indicators: &'static mut Vec<StatusNotifierItem> = Box::leak(Box::new(Vec::new()));
-    for indicator in host.get_registered_status_notifier_items()? {
-        app_indicators.push(StatusNotifierItem::new(indicator, host_connection)?);
-    }
-
-    // Now that we have all of the active status notifiers, we need to do a few things with them:
-    // 1. Get all of the information we need to display them
-    // 2. Add them to the gtk application
-    // 3. Setup watchers to check for changes to the app indicators
-    // We are going to do most of these things in a separate function
-    add_app_indicators(app_indicators, &builder)?;
-
-    gtk::main();
-
-    // Wait until the status watcher thread has finished
-    status_watcher.join().unwrap();
-
-    Ok(())
-}
-
-fn add_app_indicators<'a>(
-    app_indicators: &'static Vec<StatusNotifierItem>,
-    builder: &Builder,
-) -> Result<(), Box<dyn Error + 'a>> {
-    // Get the box that all of the app indicators will be contained in
+    // from the host and add them to the window
     let container: gtk::Box = builder.get_object("items").unwrap();
+    let mut app_indicators: HashMap<String, AppIndicator> = HashMap::new();
 
     // Remove the current contents of the box
     for item in container.get_children() {
         container.remove(&item);
     }
 
-    // Loop through each app indicator
-    for app in app_indicators {
-        let status = app.get_status()?;
+    for indicator in host.get_registered_status_notifier_items()? {
+        add_app_indicator(
+            &container,
+            &mut app_indicators,
+            indicator,
+            host_connection,
+            &sender,
+            settings,
+            1,
+        );
+    }
 
-        // We will not display app indicator that are in a passive state
-        if status == "Passive" {
-            continue;
+    // From now on, keep the window up to date with the app indicators as they change
+    receiver.attach(None, move |event| {
+        match event {
+            Event::ItemRegistered(indicator) => add_app_indicator(
+                &container,
+                &mut app_indicators,
+                indicator,
+                host_connection,
+                &sender,
+                settings,
+                1,
+            ),
+            Event::RetryItem(indicator, attempt) => {
+                // Items that unregistered in the meantime are not brought back
+                let registered = host
+                    .get_registered_status_notifier_items()
+                    .unwrap_or_default();
+
+                if registered.contains(&indicator) {
+                    add_app_indicator(
+                        &container,
+                        &mut app_indicators,
+                        indicator,
+                        host_connection,
+                        &sender,
+                        settings,
+                        attempt,
+                    )
+                }
+            }
+            Event::ItemUnregistered(indicator) => {
+                remove_app_indicator(&container, &mut app_indicators, &indicator)
+            }
+            event => {
+                let indicator = event.indicator().to_string();
+
+                match update_app_indicator(&mut app_indicators, event) {
+                    Ok(()) => {}
+                    // The item left the bus without unregistering
+                    Err(TrayError::ServiceUnknown(_)) => {
+                        remove_app_indicator(&container, &mut app_indicators, &indicator)
+                    }
+                    Err(error) => {
+                        eprintln!("Failed to update app indicator {}: {}", indicator, error)
+                    }
+                }
+            }
         }
 
-        let theme_path = app.get_icon_theme_path()?;
+        glib::Continue(true)
+    });
 
-        // This will be the icon of the app indicator
-        let image;
+    // Run the signal handlers of the host connection from the GTK main loop
+    dbus_source::attach(host_connection);
 
-        if status == "Active" {
-            let icon_name = app.get_icon_name()?;
+    gtk::main();
 
-            if theme_path != "" {
-                // If there is a theme path, you use that
+    // Wait until the status watcher thread has finished
+    status_watcher.join().unwrap();
 
-                let image_path = PathBuf::from(&format!("{}/{}.png", theme_path, icon_name));
-                println!("{:?}", image_path);
-                image = Image::from_file(image_path);
-            } else {
-                // Otherwise use an icon name
+    Ok(())
+}
 
-                image = Image::from_icon_name(Some(&icon_name), IconSize::SmallToolbar);
+/// Creates the button for a single app indicator and adds it to the container. Items that
+/// do not answer in time are tried again later, other items that fail to load are skipped.
+fn add_app_indicator(
+    container: &gtk::Box,
+    app_indicators: &mut HashMap<String, AppIndicator>,
+    indicator: String,
+    connection: &'static Connection,
+    sender: &glib::Sender<Event>,
+    settings: Settings,
+    attempt: u32,
+) {
+    // Apps may register the same item more than once
+    if app_indicators.contains_key(&indicator) {
+        return;
+    }
+
+    let app_indicator =
+        match AppIndicator::new(indicator.clone(), connection, sender.clone(), settings) {
+            Ok(app_indicator) => app_indicator,
+            // Items that disappeared straight away are not worth mentioning
+            Err(TrayError::ServiceUnknown(_)) => return,
+            Err(TrayError::Timeout(_)) if attempt < LOAD_ATTEMPTS => {
+                let (sender, delay) = (sender.clone(), RETRY_DELAY << (attempt - 1));
+                glib::timeout_add_local(delay, move || {
+                    let _ = sender.send(Event::RetryItem(indicator.clone(), attempt + 1));
+                    glib::Continue(false)
+                });
+                return;
+            }
+            Err(error) => {
+                eprintln!("Failed to add app indicator {}: {}", indicator, error);
+                return;
             }
-        } else {
-            // Must be NeedsAttention
-            let icon_name = app.get_attention_icon_name()?;
+        };
 
-            if theme_path != "" {
-                // If there is a theme path, you use that
+    // Add button to the window
+    container.pack_start(app_indicator.button(), false, false, 0);
 
-                let image_path = PathBuf::from(&format!("{}/{}.png", theme_path, icon_name));
-                println!("{:?}", image_path);
-                image = Image::from_file(image_path);
-            } else {
-                // Otherwise use an icon name
+    app_indicators.insert(indicator, app_indicator);
+}
 
-                image = Image::from_icon_name(Some(&icon_name), IconSize::SmallToolbar);
+/// Removes the button of a single app indicator, leaving all other buttons untouched
+fn remove_app_indicator(
+    container: &gtk::Box,
+    app_indicators: &mut HashMap<String, AppIndicator>,
+    indicator: &str,
+) {
+    if let Some(app_indicator) = app_indicators.remove(indicator) {
+        container.remove(app_indicator.button());
+    }
+}
+
+/// Applies a change signalled by an item to its button only
+fn update_app_indicator(
+    app_indicators: &mut HashMap<String, AppIndicator>,
+    event: Event,
+) -> Result<(), TrayError> {
+    match event {
+        Event::NewIcon(indicator)
+        | Event::NewOverlayIcon(indicator)
+        | Event::ScaleFactorChanged(indicator) => {
+            if let Some(app_indicator) = app_indicators.get_mut(&indicator) {
+                app_indicator.update_icon()?;
             }
         }
-
-        // Create the button for the app indicator
-        let button = ButtonBuilder::new().image(&image).visible(true).build();
-
-        // Create a new instance of the App for click events
-        let app_indicator = app.to_owned();
-        button.connect_clicked(move |a| {
-            println!("Click");
-            app_indicator.secondary_activate(0, 0).unwrap();
-        });
-
-        // Add button to the window
-        container.pack_start(&button, false, false, 0);
+        Event::NewAttentionIcon(indicator) => {
+            if let Some(app_indicator) = app_indicators.get_mut(&indicator) {
+                app_indicator.update_attention_icon()?;
+            }
+        }
+        Event::NewStatus(indicator, status) => {
+            if let Some(app_indicator) = app_indicators.get_mut(&indicator) {
+                app_indicator.update_status(status)?;
+            }
+        }
+        Event::NewIconThemePath(indicator) => {
+            if let Some(app_indicator) = app_indicators.get_mut(&indicator) {
+                app_indicator.update_icon_theme_path()?;
+            }
+        }
+        Event::NewTitle(indicator) | Event::NewToolTip(indicator) => {
+            if let Some(app_indicator) = app_indicators.get(&indicator) {
+                app_indicator.update_tool_tip()?;
+            }
+        }
+        Event::MenuLayoutUpdated(indicator, revision, parent) => {
+            if let Some(app_indicator) = app_indicators.get(&indicator) {
+                app_indicator.update_menu_layout(revision, parent);
+            }
+        }
+        Event::MenuPropertiesUpdated(indicator, updates) => {
+            if let Some(app_indicator) = app_indicators.get(&indicator) {
+                app_indicator.update_menu_properties(updates);
+            }
+        }
+        _ => {}
     }
 
     Ok(())
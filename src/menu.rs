@@ -0,0 +1,420 @@
+/**
+    tray (c) dotHQ 2021
+    A standalone tray application
+
+    Renders the DBusMenu of an app indicator as a native GTK menu. Activating an entry
+    is sent back to the app, which does the actual work. Once the layout has been fetched,
+    the menu is patched from the signals of the app instead of being rebuilt, so changes
+    show up in a menu that is already open.
+
+    This Source Code Form is subject to the terms of the Mozilla Public
+    License, v. 2.0. If a copy of the MPL was not distributed with this
+    file, You can obtain one at http://mozilla.org/MPL/2.0/.
+*/
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    rc::{Rc, Weak},
+};
+
+use gdk_pixbuf::{prelude::*, PixbufLoader};
+use glib::SignalHandlerId;
+use gtk::{
+    prelude::*, CheckMenuItem, IconSize, Image, Label, MenuBuilder, Orientation, SeparatorMenuItem,
+};
+
+use crate::{
+    dbus_menu::{DBusMenu, MenuItem, MenuItemType, MenuProperty, ToggleState, ToggleType},
+    error::TrayError,
+};
+
+const ICON_SIZE: IconSize = IconSize::Menu;
+
+/// The widget of a single entry
+struct Entry {
+    widget: gtk::MenuItem,
+    // Blocked while the state of a check item is changed by the app, so that does not
+    // click the item
+    activate_handler: Option<SignalHandlerId>,
+}
+
+pub struct Menu {
+    // Lets the handlers of submenus get back to the menu
+    this: Weak<RefCell<Menu>>,
+    dbus_menu: DBusMenu<'static>,
+    menu: gtk::Menu,
+    revision: u32,
+    root: Option<MenuItem>,
+    entries: HashMap<i32, Entry>,
+}
+
+impl Menu {
+    pub fn new<P: IsA<gtk::Widget>>(
+        dbus_menu: DBusMenu<'static>,
+        attach_widget: &P,
+    ) -> Rc<RefCell<Self>> {
+        let menu = MenuBuilder::new().attach_widget(attach_widget).build();
+        connect_visibility_events(&menu, 0, &dbus_menu);
+
+        Rc::new_cyclic(|this| {
+            RefCell::new(Menu {
+                this: this.clone(),
+                dbus_menu,
+                menu,
+                revision: 0,
+                root: None,
+                entries: HashMap::new(),
+            })
+        })
+    }
+
+    pub fn gtk_menu(&self) -> &gtk::Menu {
+        &self.menu
+    }
+
+    /// Gives the app a chance to fill in the menu before it is popped up. Submenus get the
+    /// same chance whenever they are shown, as some apps only add their items by then.
+    pub fn prepare(&mut self) -> Result<(), TrayError> {
+        let needs_update = self.dbus_menu.about_to_show(0).unwrap_or_else(|error| {
+            eprintln!("AboutToShow failed for {:?}: {}", self.dbus_menu, error);
+            false
+        });
+
+        // Nothing is known about the menu until the root has been fetched
+        if self.root.is_none() {
+            self.reload()
+        } else if needs_update {
+            self.refresh(0)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Lets the app update the children of `id` before they are shown, and fetches them
+    /// again when the app asks for it
+    fn about_to_show(&mut self, id: i32) -> Result<(), TrayError> {
+        if self.dbus_menu.about_to_show(id)? {
+            self.refresh(id)?;
+        }
+
+        Ok(())
+    }
+
+    /// Fetches the whole layout and rebuilds the menu
+    pub fn reload(&mut self) -> Result<(), TrayError> {
+        let (revision, root) = self.dbus_menu.get_root()?;
+
+        self.entries.clear();
+        self.populate(&self.menu.clone(), &root);
+
+        self.revision = revision;
+        self.root = Some(root);
+
+        Ok(())
+    }
+
+    /// Handles LayoutUpdated by fetching and rebuilding the children of `parent` only
+    pub fn update_layout(&mut self, revision: u32, parent: i32) -> Result<(), TrayError> {
+        // Menus that have not been shown yet are fetched in full when they are, and
+        // revisions that are older than the cached layout are already included in it
+        if self.root.is_none() || revision < self.revision {
+            return Ok(());
+        }
+
+        self.refresh(parent)
+    }
+
+    /// Fetches the children of `parent` again and rebuilds them
+    fn refresh(&mut self, parent: i32) -> Result<(), TrayError> {
+        let root = match &self.root {
+            Some(root) => root,
+            None => return self.reload(),
+        };
+
+        let stale_ids = match root.find(parent) {
+            Some(old) => descendant_ids(old),
+            // The parent is new to us, so the layout around it has to be fetched as well
+            None => return self.reload(),
+        };
+        let is_root = root.id == parent;
+
+        let (revision, item) = self.dbus_menu.get_layout(parent, -1)?;
+        self.revision = self.revision.max(revision);
+
+        for id in stale_ids {
+            self.entries.remove(&id);
+        }
+
+        if is_root {
+            self.populate(&self.menu.clone(), &item);
+        } else {
+            let submenu = self
+                .entries
+                .get(&parent)
+                .and_then(|entry| entry.widget.get_submenu())
+                .and_then(|submenu| submenu.downcast::<gtk::Menu>().ok());
+
+            match submenu {
+                // Filling the existing submenu keeps it open if it is being shown
+                Some(submenu) if item.is_submenu() => {
+                    self.populate(&submenu, &item);
+                    self.update_entry(&item);
+                }
+                _ => self.replace_entry(&item),
+            }
+        }
+
+        if let Some(node) = self.root.as_mut().and_then(|root| root.find_mut(parent)) {
+            *node = item;
+        }
+
+        Ok(())
+    }
+
+    /// Handles ItemsPropertiesUpdated by changing the affected entries only
+    pub fn update_properties(&mut self, updates: Vec<(i32, Vec<MenuProperty>)>) {
+        for (id, properties) in updates {
+            let item = match self.root.as_mut().and_then(|root| root.find_mut(id)) {
+                Some(item) => item,
+                None => continue,
+            };
+
+            // Everything else changes the kind of widget that is needed
+            let has_icon = item.icon_name.is_some() || item.icon_data.is_some();
+            let in_place = properties.iter().all(|property| match property {
+                MenuProperty::Enabled(_)
+                | MenuProperty::Visible(_)
+                | MenuProperty::ToggleState(_)
+                | MenuProperty::Shortcut(_) => true,
+                MenuProperty::Label(_) => !has_icon,
+                _ => false,
+            });
+
+            for property in properties {
+                item.apply(property);
+            }
+
+            let item = item.clone();
+            if in_place {
+                self.update_entry(&item);
+            } else {
+                self.replace_entry(&item);
+            }
+        }
+    }
+
+    /// Replaces the contents of `menu` with the children of `parent`
+    fn populate(&mut self, menu: &gtk::Menu, parent: &MenuItem) {
+        for child in menu.get_children() {
+            menu.remove(&child);
+        }
+
+        for item in &parent.children {
+            menu.append(&self.menu_item(item));
+        }
+    }
+
+    /// Creates the GTK widget for a single entry, including its submenu
+    fn menu_item(&mut self, item: &MenuItem) -> gtk::MenuItem {
+        let widget: gtk::MenuItem = match (&item.item_type, &item.toggle_type) {
+            (MenuItemType::Separator, _) => SeparatorMenuItem::new().upcast(),
+            (_, ToggleType::Checkmark) | (_, ToggleType::Radio) => {
+                // The app decides which radio item is active, so they are not put in a GTK
+                // radio group that would toggle its other items on its own
+                let check = CheckMenuItem::with_mnemonic(&item.label);
+                check.set_draw_as_radio(item.toggle_type == ToggleType::Radio);
+                check.set_active(item.toggle_state == ToggleState::On);
+                check.set_inconsistent(item.toggle_state == ToggleState::Indeterminate);
+                check.upcast()
+            }
+            _ => match icon_image(item) {
+                Some(image) => {
+                    let widget = gtk::MenuItem::new();
+                    let content = gtk::Box::new(Orientation::Horizontal, 6);
+                    let label = Label::with_mnemonic(Some(&item.label));
+                    label.set_xalign(0.0);
+                    label.set_mnemonic_widget(Some(&widget));
+
+                    content.pack_start(&image, false, false, 0);
+                    content.pack_start(&label, true, true, 0);
+                    widget.add(&content);
+                    widget
+                }
+                None => gtk::MenuItem::with_mnemonic(&item.label),
+            },
+        };
+
+        widget.set_sensitive(item.enabled);
+
+        let mut activate_handler = None;
+        if item.is_submenu() {
+            let submenu = gtk::Menu::new();
+            connect_about_to_show(&submenu, item.id, &self.this);
+            connect_visibility_events(&submenu, item.id, &self.dbus_menu);
+            self.populate(&submenu, item);
+            widget.set_submenu(Some(&submenu));
+        } else if item.item_type != MenuItemType::Separator {
+            // Items are connected after their state is set, so setting it does not click them
+            let (id, dbus_menu) = (item.id, self.dbus_menu.clone());
+            activate_handler = Some(widget.connect_activate(move |_| {
+                if let Err(error) = dbus_menu.event(id, "clicked", gtk::get_current_event_time()) {
+                    eprintln!("Failed to activate menu item {}: {}", id, error);
+                }
+            }));
+        }
+
+        if item.visible {
+            widget.show_all();
+        }
+
+        self.entries.insert(
+            item.id,
+            Entry {
+                widget: widget.clone(),
+                activate_handler,
+            },
+        );
+
+        widget
+    }
+
+    /// Applies the properties that can be changed without creating a new widget
+    fn update_entry(&self, item: &MenuItem) {
+        let entry = match self.entries.get(&item.id) {
+            Some(entry) => entry,
+            None => return,
+        };
+        let widget = &entry.widget;
+
+        widget.set_sensitive(item.enabled);
+        if item.visible {
+            widget.show_all();
+        } else {
+            widget.hide();
+        }
+
+        if item.item_type == MenuItemType::Separator {
+            return;
+        }
+
+        if item.icon_name.is_none() && item.icon_data.is_none() {
+            widget.set_label(&item.label);
+        }
+
+        if let Some(check) = widget.downcast_ref::<CheckMenuItem>() {
+            if let Some(handler) = &entry.activate_handler {
+                widget.block_signal(handler);
+            }
+
+            check.set_active(item.toggle_state == ToggleState::On);
+            check.set_inconsistent(item.toggle_state == ToggleState::Indeterminate);
+
+            if let Some(handler) = &entry.activate_handler {
+                widget.unblock_signal(handler);
+            }
+        }
+    }
+
+    /// Swaps the widget of an entry for a new one at the same position
+    fn replace_entry(&mut self, item: &MenuItem) {
+        let old = match self.entries.remove(&item.id) {
+            Some(entry) => entry.widget,
+            None => return,
+        };
+
+        let parent = match old
+            .get_parent()
+            .and_then(|parent| parent.downcast::<gtk::Menu>().ok())
+        {
+            Some(parent) => parent,
+            None => return,
+        };
+
+        let position = parent
+            .get_children()
+            .iter()
+            .position(|child| child == old.upcast_ref::<gtk::Widget>())
+            .unwrap_or_default();
+
+        let widget = self.menu_item(item);
+        parent.insert(&widget, position as i32);
+        parent.remove(&old);
+    }
+}
+
+/// Sends AboutToShow for the submenu of item `id` whenever it is shown. Submenus that were
+/// added by a refresh are covered as well, as they are connected when they are created.
+fn connect_about_to_show(submenu: &gtk::Menu, id: i32, menu: &Weak<RefCell<Menu>>) {
+    let menu = menu.clone();
+    submenu.connect_show(move |_| {
+        let menu = match menu.upgrade() {
+            Some(menu) => menu,
+            None => return,
+        };
+
+        // The menu is busy when the submenu is shown while it is being rebuilt
+        let result = match menu.try_borrow_mut() {
+            Ok(mut menu) => menu.about_to_show(id),
+            Err(_) => return,
+        };
+
+        if let Err(error) = result {
+            eprintln!("AboutToShow failed for menu item {}: {}", id, error);
+        }
+    });
+}
+
+/// Tells the app when the menu of item `id` is opened and closed
+fn connect_visibility_events(menu: &gtk::Menu, id: i32, dbus_menu: &DBusMenu<'static>) {
+    for &(event_id, hidden) in &[("opened", false), ("closed", true)] {
+        let dbus_menu = dbus_menu.clone();
+        let handler = move |_: &gtk::Menu| {
+            if let Err(error) = dbus_menu.event(id, event_id, gtk::get_current_event_time()) {
+                eprintln!(
+                    "Failed to send {} for menu item {}: {}",
+                    event_id, id, error
+                );
+            }
+        };
+
+        if hidden {
+            menu.connect_hide(handler);
+        } else {
+            menu.connect_show(handler);
+        }
+    }
+}
+
+/// The ids of all items below `item`
+fn descendant_ids(item: &MenuItem) -> Vec<i32> {
+    item.children
+        .iter()
+        .flat_map(|child| {
+            let mut ids = descendant_ids(child);
+            ids.push(child.id);
+            ids
+        })
+        .collect()
+}
+
+/// The icon of an entry, either from the icon theme or from the PNG data the app sent
+fn icon_image(item: &MenuItem) -> Option<Image> {
+    if let Some(icon_name) = &item.icon_name {
+        return Some(Image::from_icon_name(Some(icon_name), ICON_SIZE));
+    }
+
+    let data = item.icon_data.as_ref()?;
+    let loader = PixbufLoader::new();
+
+    if let Err(error) = loader.write(data).and_then(|_| loader.close()) {
+        eprintln!(
+            "Failed to load the icon of menu item {}: {}",
+            item.id, error
+        );
+        return None;
+    }
+
+    loader
+        .get_pixbuf()
+        .map(|pixbuf| Image::from_pixbuf(Some(&pixbuf)))
+}
@@ -0,0 +1,188 @@
+/**
+    tray (c) dotHQ 2021
+    A standalone tray application
+
+    Decoding of the `a(iiay)` pixmap properties of status notifier items. Each pixmap is
+    a width, a height and ARGB32 pixel data in network byte order. Apps usually send the
+    same image at several sizes, so a renderer picks the one that fits best.
+
+    This Source Code Form is subject to the terms of the Mozilla Public
+    License, v. 2.0. If a copy of the MPL was not distributed with this
+    file, You can obtain one at http://mozilla.org/MPL/2.0/.
+*/
+use std::error::Error;
+
+use gdk_pixbuf::{Colorspace, InterpType, Pixbuf};
+
+/// The raw representation of a pixmap on the bus
+pub type RawPixmap = (i32, i32, Vec<u8>);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IconPixmap {
+    pub width: i32,
+    pub height: i32,
+    pub data: Vec<u8>, // ARGB32, network byte order
+}
+
+impl IconPixmap {
+    /// Validates that `data` holds exactly `width * height` ARGB32 pixels
+    pub fn new(width: i32, height: i32, data: Vec<u8>) -> Result<Self, Box<dyn Error>> {
+        if width <= 0 || height <= 0 {
+            return Err(format!("Invalid pixmap size {}x{}", width, height).into());
+        }
+
+        let expected = (width as usize)
+            .checked_mul(height as usize)
+            .and_then(|pixels| pixels.checked_mul(4))
+            .ok_or_else(|| format!("Pixmap size {}x{} is too large", width, height))?;
+
+        if data.len() != expected {
+            return Err(format!(
+                "Pixmap of {}x{} should be {} bytes long, but is {}",
+                width,
+                height,
+                expected,
+                data.len()
+            )
+            .into());
+        }
+
+        Ok(IconPixmap {
+            width,
+            height,
+            data,
+        })
+    }
+
+    /// Parses a pixmap property, leaving out the pixmaps that are malformed
+    pub fn from_raw(pixmaps: Vec<RawPixmap>) -> Vec<Self> {
+        pixmaps
+            .into_iter()
+            .filter_map(
+                |(width, height, data)| match IconPixmap::new(width, height, data) {
+                    Ok(pixmap) => Some(pixmap),
+                    Err(error) => {
+                        eprintln!("Ignoring pixmap: {}", error);
+                        None
+                    }
+                },
+            )
+            .collect()
+    }
+
+    /// Converts the pixmap to an RGBA pixbuf
+    pub fn to_pixbuf(&self) -> Pixbuf {
+        Pixbuf::from_mut_slice(
+            self.to_rgba(),
+            Colorspace::Rgb,
+            true,
+            8,
+            self.width,
+            self.height,
+            self.width * 4,
+        )
+    }
+
+    /// Reorders the ARGB pixel data into the RGBA that GdkPixbuf uses
+    fn to_rgba(&self) -> Vec<u8> {
+        let mut rgba = Vec::with_capacity(self.data.len());
+
+        for argb in self.data.chunks_exact(4) {
+            rgba.extend_from_slice(&[argb[1], argb[2], argb[3], argb[0]]);
+        }
+
+        rgba
+    }
+}
+
+/// Picks the pixmap that fits into a square of `size` best. That is the smallest pixmap
+/// that is at least as large as `size`, or the largest pixmap if all of them are smaller.
+pub fn best_pixmap(pixmaps: &[IconPixmap], size: i32) -> Option<&IconPixmap> {
+    let fits = |pixmap: &&IconPixmap| pixmap.width.max(pixmap.height) >= size;
+
+    pixmaps
+        .iter()
+        .filter(fits)
+        .min_by_key(|pixmap| pixmap.width.max(pixmap.height))
+        .or_else(|| {
+            pixmaps
+                .iter()
+                .max_by_key(|pixmap| pixmap.width.max(pixmap.height))
+        })
+}
+
+/// Creates a pixbuf from the best fitting pixmap, scaled to fit into a square of `size`
+pub fn pixbuf_for_size(pixmaps: &[IconPixmap], size: i32) -> Option<Pixbuf> {
+    let pixmap = best_pixmap(pixmaps, size)?;
+    let pixbuf = pixmap.to_pixbuf();
+
+    let largest = pixmap.width.max(pixmap.height);
+    if largest == size {
+        return Some(pixbuf);
+    }
+
+    // Keep the aspect ratio of pixmaps that are not square
+    let width = (pixmap.width * size / largest).max(1);
+    let height = (pixmap.height * size / largest).max(1);
+
+    pixbuf.scale_simple(width, height, InterpType::Bilinear)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pixmap(width: i32, height: i32) -> IconPixmap {
+        IconPixmap::new(width, height, vec![0; (width * height * 4) as usize]).unwrap()
+    }
+
+    #[test]
+    fn accepts_matching_data() {
+        let pixmap = IconPixmap::new(2, 1, vec![1, 2, 3, 4, 5, 6, 7, 8]).unwrap();
+        assert_eq!((pixmap.width, pixmap.height), (2, 1));
+        assert_eq!(pixmap.data, vec![1, 2, 3, 4, 5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn rejects_invalid_sizes() {
+        assert!(IconPixmap::new(0, 1, Vec::new()).is_err());
+        assert!(IconPixmap::new(1, 0, Vec::new()).is_err());
+        assert!(IconPixmap::new(-1, -1, vec![0; 4]).is_err());
+        assert!(IconPixmap::new(i32::MAX, i32::MAX, Vec::new()).is_err());
+    }
+
+    #[test]
+    fn rejects_data_of_the_wrong_length() {
+        assert!(IconPixmap::new(2, 2, vec![0; 15]).is_err());
+        assert!(IconPixmap::new(2, 2, vec![0; 17]).is_err());
+    }
+
+    #[test]
+    fn leaves_out_malformed_pixmaps() {
+        let pixmaps = IconPixmap::from_raw(vec![(1, 1, vec![0; 4]), (1, 1, vec![0; 3])]);
+        assert_eq!(pixmaps, vec![pixmap(1, 1)]);
+    }
+
+    #[test]
+    fn moves_alpha_to_the_end() {
+        let pixmap = IconPixmap::new(2, 1, vec![0xff, 0x10, 0x20, 0x30, 0x80, 1, 2, 3]).unwrap();
+        assert_eq!(
+            pixmap.to_rgba(),
+            vec![0x10, 0x20, 0x30, 0xff, 1, 2, 3, 0x80]
+        );
+    }
+
+    #[test]
+    fn picks_the_smallest_pixmap_that_fits() {
+        let pixmaps = vec![pixmap(64, 64), pixmap(16, 16), pixmap(24, 24)];
+        assert_eq!(best_pixmap(&pixmaps, 20), Some(&pixmaps[2]));
+        assert_eq!(best_pixmap(&pixmaps, 16), Some(&pixmaps[1]));
+    }
+
+    #[test]
+    fn picks_the_largest_pixmap_when_none_fits() {
+        let pixmaps = vec![pixmap(16, 16), pixmap(22, 22)];
+        assert_eq!(best_pixmap(&pixmaps, 32), Some(&pixmaps[1]));
+        assert_eq!(best_pixmap(&[], 32), None);
+    }
+}
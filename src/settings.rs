@@ -0,0 +1,52 @@
+/**
+    tray (c) dotHQ 2021
+    A standalone tray application
+
+    User preferences. They are read from the environment when the tray starts:
+
+        TRAY_INVERT_SCROLL=1        Reverses the direction of scrolling over tray icons
+        TRAY_ATTENTION_TIMEOUT=30   Stops animating items that need attention after this
+                                    many seconds, rather than only once they are handled
+
+    This Source Code Form is subject to the terms of the Mozilla Public
+    License, v. 2.0. If a copy of the MPL was not distributed with this
+    file, You can obtain one at http://mozilla.org/MPL/2.0/.
+*/
+use std::{env, time::Duration};
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Settings {
+    pub invert_scroll: bool,
+    pub attention_timeout: Option<Duration>,
+}
+
+impl Settings {
+    pub fn from_env() -> Self {
+        Settings {
+            invert_scroll: env_flag("TRAY_INVERT_SCROLL"),
+            attention_timeout: env_seconds("TRAY_ATTENTION_TIMEOUT"),
+        }
+    }
+}
+
+/// Whether a flag variable is set to something that means yes
+fn env_flag(name: &str) -> bool {
+    match env::var(name) {
+        Ok(value) => matches!(
+            value.trim().to_lowercase().as_str(),
+            "1" | "true" | "yes" | "on"
+        ),
+        Err(_) => false,
+    }
+}
+
+/// A positive number of seconds
+fn env_seconds(name: &str) -> Option<Duration> {
+    let seconds: u64 = env::var(name).ok()?.trim().parse().ok()?;
+
+    if seconds > 0 {
+        Some(Duration::from_secs(seconds))
+    } else {
+        None
+    }
+}
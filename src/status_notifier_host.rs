@@ -1,17 +1,32 @@
-use std::{error::Error, fmt, time::Duration};
+use std::{fmt, process, time::Duration};
 
 use dbus::{
-    arg::{AppendAll, Get, ReadAll, RefArg},
-    blocking::{stdintf::org_freedesktop_dbus::Properties, Connection, MakeSignal, Proxy},
-    channel::Token,
+    arg::{AppendAll, Get, ReadAll},
+    blocking::{Connection, MakeSignal, Proxy},
+    channel::{MatchingReceiver, Token},
     message::SignalArgs,
+    strings::{BusName, Path},
     Message,
 };
 
+use crate::{
+    dbus_menu::DBusMenu,
+    dbus_source,
+    error::{self, TrayError},
+    item_properties::ItemProperties,
+    pixmap::{IconPixmap, RawPixmap},
+    tool_tip::{RawToolTip, ToolTip},
+};
+
 use crate::interfaces::{
+    freedesktop_dbus::OrgFreedesktopDBusNameOwnerChanged,
     status_notifier_item::{
         OrgKdeStatusNotifierItemNewAttentionIcon, OrgKdeStatusNotifierItemNewIcon,
-        OrgKdeStatusNotifierItemNewStatus, OrgKdeStatusNotifierItemNewTitle,
+        OrgKdeStatusNotifierItemNewIconThemePath, OrgKdeStatusNotifierItemNewStatus,
+        OrgKdeStatusNotifierItemNewTitle, OrgKdeStatusNotifierItemXAyatanaNewLabel,
+    },
+    status_notifier_item_ext::{
+        OrgKdeStatusNotifierItemNewOverlayIcon, OrgKdeStatusNotifierItemNewToolTip,
     },
     status_notifier_watcher::{
         OrgKdeStatusNotifierWatcherStatusNotifierItemRegistered,
@@ -20,6 +35,10 @@ use crate::interfaces::{
 };
 
 const TIMEOUT: Duration = Duration::from_millis(50);
+const REGISTER_TIMEOUT: Duration = Duration::from_millis(1000);
+const WATCHER_NAME: &str = "org.kde.StatusNotifierWatcher";
+const WATCHER_PATH: &str = "/StatusNotifierWatcher";
+const DEFAULT_ITEM_PATH: &str = "/StatusNotifierItem";
 
 #[derive(Clone)]
 pub struct StatusNotifierHost<'conn> {
@@ -28,45 +47,95 @@ pub struct StatusNotifierHost<'conn> {
 }
 
 impl<'conn> StatusNotifierHost<'conn> {
-    pub fn new(conn: &'conn Connection) -> Result<StatusNotifierHost, Box<dyn Error>> {
-        let watcher = conn.with_proxy(
-            "org.kde.StatusNotifierWatcher",
-            "/StatusNotifierWatcher",
-            TIMEOUT,
-        );
+    pub fn new(conn: &'conn Connection) -> Result<StatusNotifierHost, TrayError> {
+        let watcher = conn.with_proxy(WATCHER_NAME, WATCHER_PATH, TIMEOUT);
 
         Ok(StatusNotifierHost { conn, watcher })
     }
 
-    pub fn get_protocol_version(&self) -> Result<u8, Box<dyn Error>> {
-        let (version,): (u8,) = self
-            .watcher
-            .get("org.kde.StatusNotifierWatcher", "ProtocolVersion")?;
+    /// Claims `org.kde.StatusNotifierHost-<pid>` and registers it with whichever watcher
+    /// currently owns `org.kde.StatusNotifierWatcher`. When the watcher name changes owner
+    /// (it started late, restarted, or another watcher replaced it) the host is registered
+    /// again, so failing to register now is not fatal.
+    pub fn register(&self) -> Result<(), TrayError> {
+        let service = format!("org.kde.StatusNotifierHost-{}", process::id());
+
+        self.conn
+            .request_name(service.as_str(), false, false, true)?;
 
-        Ok(version)
+        // dbus-rs cannot match on arguments, so the rule is sent to the bus by hand. That
+        // way the bus only wakes us for the watcher name, not for every name on the bus.
+        let name_owner_rule = OrgFreedesktopDBusNameOwnerChanged::match_rule(
+            Some(&"org.freedesktop.DBus".into()),
+            None,
+        )
+        .static_clone();
+        let match_str = format!("{},arg0='{}'", name_owner_rule.match_str(), WATCHER_NAME);
+        self.conn.add_match_no_cb(&match_str)?;
+
+        let watcher_service = service.clone();
+        let on_name_owner_changed =
+            move |signal: OrgFreedesktopDBusNameOwnerChanged, conn: &Connection, _: &Message| {
+                // Only a new watcher appearing is interesting
+                if signal.arg0 != WATCHER_NAME || signal.arg2.is_empty() {
+                    return true;
+                }
+
+                if let Err(error) = register_with_watcher(conn, &watcher_service) {
+                    eprintln!(
+                        "Failed to register {} with the new watcher: {}",
+                        watcher_service, error
+                    );
+                }
+
+                true
+            };
+        self.conn.start_receive(
+            name_owner_rule,
+            MakeSignal::make(on_name_owner_changed, match_str),
+        );
+
+        if let Err(error) = self.register_status_notifier_host(&service) {
+            eprintln!(
+                "Failed to register {}, waiting for a watcher: {}",
+                service, error
+            );
+        }
+
+        Ok(())
     }
 
-    pub fn get_registered_status_notifier_items(&self) -> Result<Vec<String>, Box<dyn Error>> {
-        let items: Vec<String> = self.watcher.get(
+    pub fn get_protocol_version(&self) -> Result<i32, TrayError> {
+        error::get_property(
+            &self.watcher,
             "org.kde.StatusNotifierWatcher",
-            "RegisteredStatusNotifierItems",
-        )?;
+            "ProtocolVersion",
+        )
+    }
 
-        Ok(items)
+    pub fn get_registered_status_notifier_items(&self) -> Result<Vec<String>, TrayError> {
+        error::get_property(
+            &self.watcher,
+            "org.kde.StatusNotifierWatcher",
+            "RegisteredStatusNotifierItems",
+        )
     }
 
-    pub fn get_item(&self, item: usize) -> Result<StatusNotifierItem, Box<dyn Error>> {
+    pub fn get_item(&self, item: usize) -> Result<StatusNotifierItem, TrayError> {
         let items = self.get_registered_status_notifier_items()?;
-        Ok(StatusNotifierItem::new(items[item].clone(), self.conn)?)
+        StatusNotifierItem::new(items[item].clone(), self.conn)
+    }
+
+    pub fn register_status_notifier_host(&self, service: &str) -> Result<(), TrayError> {
+        register_with_watcher(self.conn, service)
     }
 
-    // UNIMPLEMENTED: Method RegusterStatusNotifierHost
     // UNIMPLEMENTED: Method RegusterStatusNotifierItem
 
     pub fn signal<S: ReadAll + SignalArgs, F: 'static + SignalFunction<S>>(
         &self,
         f: F,
-    ) -> Result<Token, Box<dyn Error>> {
+    ) -> Result<Token, TrayError> {
         Ok(self.watcher.match_signal(f)?)
     }
 
@@ -78,7 +147,7 @@ impl<'conn> StatusNotifierHost<'conn> {
     >(
         &self,
         f: F,
-    ) -> Result<Token, Box<dyn Error>> {
+    ) -> Result<Token, TrayError> {
         self.signal(f)
     }
 
@@ -87,97 +156,183 @@ impl<'conn> StatusNotifierHost<'conn> {
     >(
         &self,
         f: F,
-    ) -> Result<Token, Box<dyn Error>> {
+    ) -> Result<Token, TrayError> {
         self.signal(f)
     }
 }
 
+/// Registers a host with the watcher. Watchers that are starting up can take a while to
+/// answer, so this waits longer than other calls.
+fn register_with_watcher(conn: &Connection, service: &str) -> Result<(), TrayError> {
+    let watcher = conn.with_proxy(WATCHER_NAME, WATCHER_PATH, REGISTER_TIMEOUT);
+    error::method_call(
+        &watcher,
+        "org.kde.StatusNotifierWatcher",
+        "RegisterStatusNotifierHost",
+        (service,),
+    )
+}
+
 pub trait SignalFunction<S: ReadAll + SignalArgs>:
     Fn(S, &Connection, &Message) -> bool + Send
 {
 }
 
+impl<S: ReadAll + SignalArgs, F: Fn(S, &Connection, &Message) -> bool + Send> SignalFunction<S>
+    for F
+{
+}
+
 /**
  * An alias to a status notifier item, for pulling data, calling methods and registering signals in an ergonomic way
  */
 #[derive(Clone)]
 pub struct StatusNotifierItem<'conn> {
     item: Proxy<'conn, &'conn Connection>,
-    menu: Option<Proxy<'conn, &'conn Connection>>,
+    menu: Option<DBusMenu<'conn>>,
 }
 
 impl<'conn> StatusNotifierItem<'conn> {
-    pub fn new(name: String, connection: &'conn Connection) -> Result<Self, Box<dyn Error>> {
-        let mut name = name.split('/');
-        let id = name.next().unwrap().to_string();
-        let path = name.collect::<Vec<&str>>();
-        let path = format!("/{}", path.join("/"));
+    /// Creates an item from a `busname/objectpath` string, as found in the watcher's
+    /// `RegisteredStatusNotifierItems`. A missing object path defaults to `/StatusNotifierItem`.
+    ///
+    /// The item is addressed by the unique name of its owner. Signals are always sent from
+    /// the unique name, and dbus-rs does not compare that against a well-known name in a
+    /// match rule. As every Qt app exports its item at `/StatusNotifierItem`, matching on
+    /// the well-known name would hand the signals of one app to the item of another.
+    pub fn new(name: String, connection: &'conn Connection) -> Result<Self, TrayError> {
+        let (id, path) = match parse_registration(&name) {
+            Some(registration) => registration,
+            None => return Err(TrayError::InvalidRegistration(name)),
+        };
 
-        let item = connection.with_proxy(id, path, TIMEOUT);
+        Ok(StatusNotifierItem {
+            item: connection.with_proxy(unique_name(connection, id)?, path, TIMEOUT),
+            menu: None,
+        })
+    }
+
+    /// Sets the DBusMenu from the Menu property, which is read along with the others. The
+    /// menu is addressed by the unique name of the item, for the same reason as the item
+    /// itself: every Qt app exports its menu at `/MenuBar`, so a well-known name would let
+    /// LayoutUpdated and ItemsPropertiesUpdated of one app patch the menu of another.
+    pub fn set_menu(&mut self, menu_path: Option<&Path<'static>>) {
+        debug_assert!(self.item.destination.starts_with(':'));
+
+        // Items without a menu either leave the property out or point it at the root
+        self.menu = match menu_path {
+            Some(path) if !["/", "/NO_DBUSMENU"].contains(&&**path) => {
+                Some(DBusMenu::new::<_, Path<'conn>>(
+                    self.item.destination.clone(),
+                    path.clone(),
+                    self.item.connection,
+                ))
+            }
+            _ => None,
+        };
+    }
 
-        Ok(StatusNotifierItem { item, menu: None })
+    /// The item's DBusMenu, if it exports one
+    pub fn menu(&self) -> Option<&DBusMenu<'conn>> {
+        self.menu.as_ref()
     }
 
-    pub fn get<R0: for<'b> Get<'b> + 'static>(
-        &self,
-        property_name: &str,
-    ) -> Result<R0, Box<dyn Error>> {
-        Ok(self.item.get("org.kde.StatusNotifierItem", property_name)?)
+    pub fn get<R0: for<'b> Get<'b> + 'static>(&self, property_name: &str) -> Result<R0, TrayError> {
+        error::get_property(&self.item, "org.kde.StatusNotifierItem", property_name)
+    }
+
+    /// Reads all properties of the item at once
+    pub fn get_properties(&self) -> Result<ItemProperties, TrayError> {
+        self.get_properties_within(self.item.timeout)
+    }
+
+    /// Reads all properties of the item, waiting up to `timeout` for the reply
+    pub fn get_properties_within(&self, timeout: Duration) -> Result<ItemProperties, TrayError> {
+        let item = Proxy::new(
+            self.item.destination.clone(),
+            self.item.path.clone(),
+            timeout,
+            self.item.connection,
+        );
+
+        let (properties,) = error::method_call(
+            &item,
+            "org.freedesktop.DBus.Properties",
+            "GetAll",
+            ("org.kde.StatusNotifierItem",),
+        )?;
+
+        Ok(properties)
+    }
+
+    /// Gets one of the `a(iiay)` pixmap properties. Malformed pixmaps are left out.
+    pub fn get_pixmap(&self, property_name: &str) -> Result<Vec<IconPixmap>, TrayError> {
+        let pixmaps: Vec<RawPixmap> = self.get(property_name)?;
+        Ok(IconPixmap::from_raw(pixmaps))
     }
 
-    pub fn get_category(&self) -> Result<String, Box<dyn Error>> {
+    pub fn get_category(&self) -> Result<String, TrayError> {
         self.get("Category")
     }
 
-    pub fn get_id(&self) -> Result<String, Box<dyn Error>> {
+    pub fn get_id(&self) -> Result<String, TrayError> {
         self.get("Id")
     }
 
-    pub fn get_title(&self) -> Result<String, Box<dyn Error>> {
+    pub fn get_title(&self) -> Result<String, TrayError> {
         self.get("Title")
     }
 
-    pub fn get_status(&self) -> Result<String, Box<dyn Error>> {
+    pub fn get_status(&self) -> Result<String, TrayError> {
         self.get("Status")
     }
 
-    pub fn get_window_id(&self) -> Result<u32, Box<dyn Error>> {
+    pub fn get_window_id(&self) -> Result<u32, TrayError> {
         self.get("WindowId")
     }
 
-    pub fn get_icon_theme_path(&self) -> Result<String, Box<dyn Error>> {
+    pub fn get_icon_theme_path(&self) -> Result<String, TrayError> {
         self.get("IconThemePath")
     }
 
-    pub fn get_icon_name(&self) -> Result<String, Box<dyn Error>> {
+    pub fn get_icon_name(&self) -> Result<String, TrayError> {
         self.get("IconName")
     }
 
-    // UNIMPLEMENTED: Gettter for IconPixmap
+    pub fn get_icon_pixmap(&self) -> Result<Vec<IconPixmap>, TrayError> {
+        self.get_pixmap("IconPixmap")
+    }
 
-    pub fn get_overlay_icon_name(&self) -> Result<String, Box<dyn Error>> {
+    pub fn get_overlay_icon_name(&self) -> Result<String, TrayError> {
         self.get("OverlayIconName")
     }
 
-    // UNIMPLEMENTED: Getter for OverlayIconPixmap
+    pub fn get_overlay_icon_pixmap(&self) -> Result<Vec<IconPixmap>, TrayError> {
+        self.get_pixmap("OverlayIconPixmap")
+    }
 
-    pub fn get_attention_icon_name(&self) -> Result<String, Box<dyn Error>> {
+    pub fn get_attention_icon_name(&self) -> Result<String, TrayError> {
         self.get("AttentionIconName")
     }
 
-    // UNIMPLEMENTED: Getter for AttentionIconPixmap
+    pub fn get_attention_icon_pixmap(&self) -> Result<Vec<IconPixmap>, TrayError> {
+        self.get_pixmap("AttentionIconPixmap")
+    }
 
-    pub fn get_attention_movie_name(&self) -> Result<String, Box<dyn Error>> {
+    pub fn get_attention_movie_name(&self) -> Result<String, TrayError> {
         self.get("AttentionMovieName")
     }
 
-    // UNIMPLEMENTED: Getter for Tooltip
+    pub fn get_tool_tip(&self) -> Result<ToolTip, TrayError> {
+        let tool_tip: RawToolTip = self.get("ToolTip")?;
+        Ok(ToolTip::from_raw(tool_tip))
+    }
 
-    pub fn get_is_menu(&self) -> Result<bool, Box<dyn Error>> {
-        self.get("IsMenu")
+    pub fn get_is_menu(&self) -> Result<bool, TrayError> {
+        self.get("ItemIsMenu")
     }
 
-    pub fn get_menu(&self) -> Result<Box<dyn RefArg>, Box<dyn Error>> {
+    pub fn get_menu(&self) -> Result<Path<'static>, TrayError> {
         self.get("Menu")
     }
 
@@ -185,50 +340,53 @@ impl<'conn> StatusNotifierItem<'conn> {
         &self,
         method_name: &str,
         args: A,
-    ) -> Result<R, Box<dyn Error>> {
-        Ok(self
-            .item
-            .method_call("org.kde.StatusNotifierItem", method_name, args)?)
+    ) -> Result<R, TrayError> {
+        error::method_call(&self.item, "org.kde.StatusNotifierItem", method_name, args)
     }
 
-    pub fn context_menu(&self, x: i32, y: i32) -> Result<(), Box<dyn Error>> {
-        self.call("ContextMenu", (x, y))?;
-        Ok(())
+    pub fn context_menu(&self, x: i32, y: i32) -> Result<(), TrayError> {
+        self.call("ContextMenu", (x, y))
     }
 
-    pub fn activate(&self, x: i32, y: i32) -> Result<(), Box<dyn Error>> {
-        self.call("Activate", (x, y))?;
-        Ok(())
+    pub fn activate(&self, x: i32, y: i32) -> Result<(), TrayError> {
+        self.call("Activate", (x, y))
     }
 
-    pub fn secondary_activate(&self, x: i32, y: i32) -> Result<(), Box<dyn Error>> {
-        self.call("SecondaryActivate", (x, y))?;
-        Ok(())
+    pub fn secondary_activate(&self, x: i32, y: i32) -> Result<(), TrayError> {
+        self.call("SecondaryActivate", (x, y))
     }
 
-    pub fn scroll(&self, delta: i32, orientation: &str) -> Result<(), Box<dyn Error>> {
-        self.call("Scroll", (delta, orientation))?;
-        Ok(())
+    /// Ayatana items get this instead of SecondaryActivate, with the time of the click
+    pub fn x_ayatana_secondary_activate(&self, timestamp: u32) -> Result<(), TrayError> {
+        self.call("XAyatanaSecondaryActivate", (timestamp,))
+    }
+
+    pub fn scroll(&self, delta: i32, orientation: &str) -> Result<(), TrayError> {
+        self.call("Scroll", (delta, orientation))
     }
 
     pub fn signal<S: ReadAll + SignalArgs, F: 'static + SignalFunction<S>>(
         &self,
         f: F,
-    ) -> Result<Token, Box<dyn Error>> {
-        Ok(self.item.match_signal(f)?)
+    ) -> Result<Token, TrayError> {
+        // Adding the match rule blocks until the bus daemon answers
+        let token = self.item.match_signal(f);
+        dbus_source::dispatch_queued(self.item.connection);
+
+        Ok(token?)
     }
 
     pub fn new_title<F: 'static + SignalFunction<OrgKdeStatusNotifierItemNewTitle>>(
         &self,
         f: F,
-    ) -> Result<Token, Box<dyn Error>> {
+    ) -> Result<Token, TrayError> {
         self.signal(f)
     }
 
     pub fn new_icon<F: 'static + SignalFunction<OrgKdeStatusNotifierItemNewIcon>>(
         &self,
         f: F,
-    ) -> Result<Token, Box<dyn Error>> {
+    ) -> Result<Token, TrayError> {
         self.signal(f)
     }
 
@@ -237,25 +395,85 @@ impl<'conn> StatusNotifierItem<'conn> {
     >(
         &self,
         f: F,
-    ) -> Result<Token, Box<dyn Error>> {
+    ) -> Result<Token, TrayError> {
+        self.signal(f)
+    }
+
+    pub fn new_overlay_icon<F: 'static + SignalFunction<OrgKdeStatusNotifierItemNewOverlayIcon>>(
+        &self,
+        f: F,
+    ) -> Result<Token, TrayError> {
         self.signal(f)
     }
 
-    // UNIMPLEMENTED: NewOverlayIcon
-    // UNIMPLEMENTED: NewToolTip
+    pub fn new_tool_tip<F: 'static + SignalFunction<OrgKdeStatusNotifierItemNewToolTip>>(
+        &self,
+        f: F,
+    ) -> Result<Token, TrayError> {
+        self.signal(f)
+    }
 
     pub fn new_status<F: 'static + SignalFunction<OrgKdeStatusNotifierItemNewStatus>>(
         &self,
         f: F,
-    ) -> Result<Token, Box<dyn Error>> {
+    ) -> Result<Token, TrayError> {
+        self.signal(f)
+    }
+
+    pub fn new_icon_theme_path<
+        F: 'static + SignalFunction<OrgKdeStatusNotifierItemNewIconThemePath>,
+    >(
+        &self,
+        f: F,
+    ) -> Result<Token, TrayError> {
+        self.signal(f)
+    }
+
+    /// Sent by Ayatana (libappindicator) items when the label next to their icon changes
+    pub fn x_ayatana_new_label<
+        F: 'static + SignalFunction<OrgKdeStatusNotifierItemXAyatanaNewLabel>,
+    >(
+        &self,
+        f: F,
+    ) -> Result<Token, TrayError> {
         self.signal(f)
     }
 }
 
+/// Looks up the unique name that currently owns `name`. Unique names are their own owner.
+fn unique_name(
+    connection: &Connection,
+    name: BusName<'static>,
+) -> Result<BusName<'static>, TrayError> {
+    if name.starts_with(':') {
+        return Ok(name);
+    }
+
+    let bus = connection.with_proxy("org.freedesktop.DBus", "/org/freedesktop/DBus", TIMEOUT);
+    let (owner,): (String,) =
+        error::method_call(&bus, "org.freedesktop.DBus", "GetNameOwner", (&*name,))?;
+
+    BusName::new(owner).map_err(TrayError::TypeMismatch)
+}
+
+/// Splits a `busname/objectpath` string into its parts. Building a proxy from names that
+/// are not valid on the bus would panic, so those are rejected.
+fn parse_registration(name: &str) -> Option<(BusName<'static>, Path<'static>)> {
+    let (id, path) = match name.find('/') {
+        Some(index) => (name[..index].to_string(), name[index..].to_string()),
+        None => (name.to_string(), DEFAULT_ITEM_PATH.to_string()),
+    };
+
+    match (BusName::new(id), Path::new(path)) {
+        (Ok(id), Ok(path)) => Some((id, path)),
+        _ => None,
+    }
+}
+
 impl<'conn> fmt::Debug for StatusNotifierItem<'conn> {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         let menu = match &self.menu {
-            Some(menu) => format!("{} {}", menu.destination, menu.path),
+            Some(menu) => format!("{:?}", menu),
             None => String::from("None"),
         };
 
@@ -268,3 +486,51 @@ impl<'conn> fmt::Debug for StatusNotifierItem<'conn> {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(name: &str) -> Option<(String, String)> {
+        parse_registration(name).map(|(id, path)| (id.to_string(), path.to_string()))
+    }
+
+    #[test]
+    fn parses_well_known_name_with_path() {
+        assert_eq!(
+            parse("org.kde.StatusNotifierItem-1-1/StatusNotifierItem"),
+            Some((
+                "org.kde.StatusNotifierItem-1-1".to_string(),
+                "/StatusNotifierItem".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn parses_unique_name_with_nested_path() {
+        assert_eq!(
+            parse(":1.42/org/ayatana/NotificationItem/nm_applet"),
+            Some((
+                ":1.42".to_string(),
+                "/org/ayatana/NotificationItem/nm_applet".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn defaults_the_object_path() {
+        assert_eq!(
+            parse(":1.42"),
+            Some((":1.42".to_string(), "/StatusNotifierItem".to_string()))
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_registrations() {
+        assert_eq!(parse(""), None);
+        assert_eq!(parse("/StatusNotifierItem"), None);
+        assert_eq!(parse("not a bus name/StatusNotifierItem"), None);
+        assert_eq!(parse(":1.42/not a path"), None);
+        assert_eq!(parse(":1.42/trailing/"), None);
+    }
+}
@@ -10,102 +10,77 @@
     License, v. 2.0. If a copy of the MPL was not distributed with this
     file, You can obtain one at http://mozilla.org/MPL/2.0/.
 */
-use std::{error::Error, sync::Mutex};
-
-use dbus::blocking::Connection;
+use std::{
+    collections::HashMap,
+    error::Error,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use dbus::{
+    arg::{PropMap, Variant},
+    blocking::Connection,
+    channel::{MatchingReceiver, Sender},
+    message::{MatchRule, SignalArgs},
+    Message, Path,
+};
 use dbus_crossroads::Crossroads;
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
-pub enum Category {
-    ApplicationStatus,
-    Communications,
-    SystemServices,
-    Hardware,
-    Unknown,
-}
-
-impl Category {
-    pub fn from_str(s: &str) -> Self {
-        match s {
-            "ApplicationStatus" => Category::ApplicationStatus,
-            "Communications" => Category::Communications,
-            "SystemServices" => Category::SystemServices,
-            "Hardware" => Category::Hardware,
-            _ => Category::Unknown,
-        }
-    }
-
-    pub fn to_str(&self) -> &str {
-        match self {
-            Category::ApplicationStatus => "ApplicationStatus",
-            Category::Communications => "Communications",
-            Category::SystemServices => "SystemServices",
-            Category::Hardware => "Hardware",
-            Category::Unknown => "Unknown",
-        }
-    }
-
-    pub fn to_string(&self) -> String {
-        self.to_str().to_string()
-    }
-}
-
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
-pub enum Status {
-    Passive,
-    Active,
-    NeedsAttention,
-}
-
-impl Status {
-    pub fn from_str(s: &str) -> Self {
-        match s {
-            "Passive" => Status::Passive,
-            "Active" => Status::Active,
-            "NeedsAttention" => Status::NeedsAttention,
-            _ => Status::Passive,
-        }
-    }
+use crate::interfaces::{
+    freedesktop_dbus::OrgFreedesktopDBusNameOwnerChanged,
+    status_notifier_watcher::{
+        OrgFreedesktopDBusPropertiesPropertiesChanged,
+        OrgKdeStatusNotifierWatcherStatusNotifierHostUnregistered,
+        OrgKdeStatusNotifierWatcherStatusNotifierItemUnregistered,
+    },
+};
 
-    pub fn to_str(&self) -> &str {
-        match self {
-            Status::Passive => "Passive",
-            Status::Active => "Active",
-            Status::NeedsAttention => "NeedsAttention",
-        }
-    }
-
-    pub fn to_string(&self) -> String {
-        self.to_str().to_string()
-    }
-}
+const WATCHER_PATH: &str = "/StatusNotifierWatcher";
+const DEFAULT_ITEM_PATH: &str = "/StatusNotifierItem";
 
 #[derive(Clone)]
 pub struct StatusNotifierItem {
-    pub service: String, //
-    pub sender: String,  //
-                         // proxy: Proxy<&Connection>,                  //
-                         // pub category: Option<Category>,           //
-                         // pub title: Option<String>,                //
-                         // pub id: Option<String>,                   //
-                         // pub status: Option<Status>,               //
-                         // pub window_id: Option<u32>,               //
-                         // pub icon_name: Option<String>,            //
-                         // pub icon_piximap: Option<()>,             // TODO
-                         // pub overlay_icon_name: Option<String>,    //
-                         // pub overlay_icon_piximap: Option<()>,     // TODO
-                         // pub attention_icon_name: Option<String>,  //
-                         // pub attention_icon_piximap: Option<()>,   // TODO
-                         // pub attention_movie_name: Option<String>, // Not implementing
-                         // pub tool_tip: Option<()>,                 // TODO
-                         // pub item_is_menu: Option<bool>,           // Not implementing
-                         // pub menu: Option<String>,                 // Not implementing
+    pub bus_name: String,    // The bus name the item is served from
+    pub object_path: String, // The object path of the item on that bus name
+    pub sender: String,      // The unique name of the connection that registered the item
+                             // proxy: Proxy<&Connection>,                  //
+                             // pub category: Option<Category>,           //
+                             // pub title: Option<String>,                //
+                             // pub id: Option<String>,                   //
+                             // pub status: Option<Status>,               //
+                             // pub window_id: Option<u32>,               //
+                             // pub icon_name: Option<String>,            //
+                             // pub icon_piximap: Option<()>,             // TODO
+                             // pub overlay_icon_name: Option<String>,    //
+                             // pub overlay_icon_piximap: Option<()>,     // TODO
+                             // pub attention_icon_name: Option<String>,  //
+                             // pub attention_icon_piximap: Option<()>,   // TODO
+                             // pub attention_movie_name: Option<String>, // Not implementing
+                             // pub tool_tip: Option<()>,                 // TODO
+                             // pub item_is_menu: Option<bool>,           // Not implementing
+                             // pub menu: Option<String>,                 // Not implementing
 }
 
 impl StatusNotifierItem {
+    /// Creates an item from the `service` argument of `RegisterStatusNotifierItem`.
+    ///
+    /// Apps pass either a bus name (well-known or unique), a bare object path on their own
+    /// connection, or occasionally both joined together. All of these are normalised into a
+    /// bus name and object path, with the object path defaulting to `/StatusNotifierItem`.
     pub fn new(service: String, sender: String) -> Self {
+        let (bus_name, object_path) = match service.find('/') {
+            // A bare object path, the item lives on the sender's connection
+            Some(0) => (sender.clone(), service),
+            // A bus name with the object path appended
+            Some(index) => (service[..index].to_string(), service[index..].to_string()),
+            // Only a bus name (or nothing at all)
+            None if service.is_empty() => (sender.clone(), DEFAULT_ITEM_PATH.to_string()),
+            None => (service, DEFAULT_ITEM_PATH.to_string()),
+        };
+
         StatusNotifierItem {
-            service,
+            bus_name,
+            object_path,
             sender,
             // proxy,
             // category: None,
@@ -128,28 +103,93 @@ impl StatusNotifierItem {
 
     // pub fn get_title(&self) -> String {}
 
+    /// The canonical `busname/objectpath` form that is handed out to hosts
     pub fn to_register_string(&self) -> String {
-        format!("{}{}", self.sender, self.service)
+        format!("{}{}", self.bus_name, self.object_path)
+    }
+}
+
+#[derive(Clone)]
+pub struct StatusNotifierHost {
+    pub service: String, // The service name passed to RegisterStatusNotifierHost
+    pub sender: String,  // The unique name of the connection that registered the host
+}
+
+impl StatusNotifierHost {
+    pub fn new(service: String, sender: String) -> Self {
+        StatusNotifierHost { service, sender }
     }
 }
 
 pub struct StatusNotifierWatcher {
     pub services: Vec<StatusNotifierItem>,
+    pub hosts: Vec<StatusNotifierHost>,
 }
 
 impl StatusNotifierWatcher {
     fn new() -> Result<Self, Box<dyn Error>> {
         Ok(StatusNotifierWatcher {
             services: Vec::new(),
+            hosts: Vec::new(),
         })
     }
 
+    pub fn is_status_notifier_host_registered(&self) -> bool {
+        !self.hosts.is_empty()
+    }
+
+    /// Removes every host that was registered by, or is known by, the bus name `name`.
+    /// Returns the service names of the removed hosts.
+    pub fn remove_hosts_owned_by(&mut self, name: &str) -> Vec<String> {
+        let (removed, hosts): (Vec<StatusNotifierHost>, Vec<StatusNotifierHost>) = self
+            .hosts
+            .drain(..)
+            .partition(|host| host.sender == name || host.service == name);
+
+        self.hosts = hosts;
+
+        removed.into_iter().map(|host| host.service).collect()
+    }
+
     pub fn services_to_register_string(&self) -> Vec<String> {
         (&self.services)
             .into_iter()
             .map(|sni| sni.to_register_string())
             .collect()
     }
+
+    /// Removes every item that was registered by, or is served from, the bus name `name`.
+    /// Returns the register strings of the removed items.
+    pub fn remove_services_owned_by(&mut self, name: &str) -> Vec<String> {
+        let (removed, services): (Vec<StatusNotifierItem>, Vec<StatusNotifierItem>) = self
+            .services
+            .drain(..)
+            .partition(|sni| sni.sender == name || sni.bus_name == name);
+
+        self.services = services;
+
+        removed
+            .into_iter()
+            .map(|sni| sni.to_register_string())
+            .collect()
+    }
+}
+
+/// Creates the `PropertiesChanged` signal that announces a new value of
+/// `IsStatusNotifierHostRegistered`
+fn host_registered_changed_signal(path: &Path<'static>, registered: bool) -> Message {
+    let mut changed_properties: PropMap = HashMap::new();
+    changed_properties.insert(
+        "IsStatusNotifierHostRegistered".to_string(),
+        Variant(Box::new(registered)),
+    );
+
+    OrgFreedesktopDBusPropertiesPropertiesChanged {
+        interface_name: "org.kde.StatusNotifierWatcher".to_string(),
+        changed_properties,
+        invalidated_properties: Vec::new(),
+    }
+    .to_emit_message(path)
 }
 
 pub fn run(inited_mutex: &Mutex<bool>) -> Result<(), Box<dyn Error>> {
@@ -173,8 +213,33 @@ pub fn run(inited_mutex: &Mutex<bool>) -> Result<(), Box<dyn Error>> {
             "RegisterStatusNotifierHost",
             ("service",),
             (),
-            |_, _, (service,): (String,)| {
+            |context, data: &mut StatusNotifierWatcher, (service,): (String,)| {
+                // Log register information to the console
                 println!("RegisterStatusNotifierHost service={}", service);
+
+                let sender = context.message().sender().unwrap().to_string();
+
+                // Ignore hosts that are already registered
+                if data.hosts.iter().any(|host| host.service == service) {
+                    return Ok(());
+                }
+
+                let was_registered = data.is_status_notifier_host_registered();
+
+                // Add the host to the data store
+                data.hosts.push(StatusNotifierHost::new(service, sender));
+
+                // Create and send the StatusNotifierHostRegistered signal
+                let signal_msg = context.make_signal("StatusNotifierHostRegistered", ());
+                context.push_msg(signal_msg);
+
+                // Let apps waiting on IsStatusNotifierHostRegistered know that it has changed
+                if !was_registered {
+                    let signal_msg = host_registered_changed_signal(context.path(), true);
+                    context.push_msg(signal_msg);
+                }
+
+                // Return
                 Ok(())
             },
         );
@@ -188,14 +253,25 @@ pub fn run(inited_mutex: &Mutex<bool>) -> Result<(), Box<dyn Error>> {
                 // Log register information to the console
                 println!("RegisterStatusNotifierItem service={}", service);
 
-                let path = context.message().sender().unwrap().to_string();
+                let sender = context.message().sender().unwrap().to_string();
+                let item = StatusNotifierItem::new(service, sender);
+                let register_string = item.to_register_string();
+
+                // Apps may register the same item more than once, hosts only need to know once
+                if data
+                    .services
+                    .iter()
+                    .any(|sni| sni.to_register_string() == register_string)
+                {
+                    return Ok(());
+                }
 
                 // Add the service to the data store
-                data.services
-                    .push(StatusNotifierItem::new(service.clone(), path));
+                data.services.push(item);
 
                 // Create and send the StatusNotifierItemRegistered signal
-                let signal_msg = context.make_signal("StatusNotifierItemRegistered", ("/",));
+                let signal_msg =
+                    context.make_signal("StatusNotifierItemRegistered", (register_string,));
                 context.push_msg(signal_msg);
 
                 // Return
@@ -220,13 +296,13 @@ pub fn run(inited_mutex: &Mutex<bool>) -> Result<(), Box<dyn Error>> {
         b.property::<Vec<String>, &str>("RegisteredStatusNotifierItems")
             .get(|_, data| Ok(data.services_to_register_string()));
         b.property::<bool, &str>("IsStatusNotifierHostRegistered")
-            .get(|_, _| Ok(true));
-        b.property::<u8, &str>("ProtocolVersion").get(|_, _| Ok(0));
+            .get(|_, data| Ok(data.is_status_notifier_host_registered()));
+        b.property::<i32, &str>("ProtocolVersion").get(|_, _| Ok(0));
     });
 
     // Insert the functionality into our watcher
     cr.insert(
-        "/StatusNotifierWatcher",
+        WATCHER_PATH,
         &[status_notifier_watcher],
         StatusNotifierWatcher::new()?,
     );
@@ -235,8 +311,119 @@ pub fn run(inited_mutex: &Mutex<bool>) -> Result<(), Box<dyn Error>> {
     *inited = true;
     drop(inited);
 
+    // Crossroads is shared between the method handler and the name owner watch below
+    let cr = Arc::new(Mutex::new(cr));
+
     // Add to the connection
-    cr.serve(&connection)?;
+    let method_cr = cr.clone();
+    connection.start_receive(
+        MatchRule::new_method_call(),
+        Box::new(move |msg, conn| {
+            method_cr.lock().unwrap().handle_message(msg, conn).unwrap();
+            true
+        }),
+    );
+
+    // When an app quits (or drops its bus name) its items have to be removed, otherwise
+    // hosts will keep displaying dead icons. The same goes for hosts that have crashed, so
+    // that apps can fall back to other ways of displaying themselves
+    let name_owner_rule =
+        OrgFreedesktopDBusNameOwnerChanged::match_rule(Some(&"org.freedesktop.DBus".into()), None)
+            .static_clone();
+    connection.add_match(
+        name_owner_rule,
+        move |signal: OrgFreedesktopDBusNameOwnerChanged, conn: &Connection, _: &Message| {
+            // A non-empty new owner means that the name has only changed hands
+            if !signal.arg2.is_empty() {
+                return true;
+            }
+
+            let path = Path::from(WATCHER_PATH);
+            let mut cr = cr.lock().unwrap();
+            let data: &mut StatusNotifierWatcher = cr.data_mut(&path).unwrap();
+
+            for service in data.remove_services_owned_by(&signal.arg0) {
+                // Log unregister information to the console
+                println!("UnregisterStatusNotifierItem service={}", service);
+
+                // Create and send the StatusNotifierItemUnregistered signal
+                let signal_msg =
+                    OrgKdeStatusNotifierWatcherStatusNotifierItemUnregistered { arg_0: service }
+                        .to_emit_message(&path);
+                let _ = conn.send(signal_msg);
+            }
+
+            let removed_hosts = data.remove_hosts_owned_by(&signal.arg0);
+
+            for service in &removed_hosts {
+                // Log unregister information to the console
+                println!("UnregisterStatusNotifierHost service={}", service);
+
+                // Create and send the StatusNotifierHostUnregistered signal
+                let signal_msg = OrgKdeStatusNotifierWatcherStatusNotifierHostUnregistered {}
+                    .to_emit_message(&path);
+                let _ = conn.send(signal_msg);
+            }
+
+            if !removed_hosts.is_empty() && !data.is_status_notifier_host_registered() {
+                let _ = conn.send(host_registered_changed_signal(&path, false));
+            }
+
+            true
+        },
+    )?;
+
+    // Serve clients forever
+    loop {
+        connection.process(Duration::from_millis(1000))?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    unreachable!()
+    fn item(service: &str) -> StatusNotifierItem {
+        StatusNotifierItem::new(service.to_string(), ":1.42".to_string())
+    }
+
+    #[test]
+    fn bare_path_is_on_the_sender() {
+        let item = item("/org/ayatana/NotificationItem/nm_applet");
+        assert_eq!(item.bus_name, ":1.42");
+        assert_eq!(item.object_path, "/org/ayatana/NotificationItem/nm_applet");
+    }
+
+    #[test]
+    fn unique_name_gets_default_path() {
+        let item = item(":1.7");
+        assert_eq!(item.bus_name, ":1.7");
+        assert_eq!(item.object_path, "/StatusNotifierItem");
+        assert_eq!(item.sender, ":1.42");
+    }
+
+    #[test]
+    fn well_known_name_gets_default_path() {
+        let item = item("org.kde.StatusNotifierItem-1234-1");
+        assert_eq!(item.bus_name, "org.kde.StatusNotifierItem-1234-1");
+        assert_eq!(item.object_path, "/StatusNotifierItem");
+    }
+
+    #[test]
+    fn empty_service_is_the_sender() {
+        let item = item("");
+        assert_eq!(item.bus_name, ":1.42");
+        assert_eq!(item.object_path, "/StatusNotifierItem");
+    }
+
+    #[test]
+    fn name_and_path_are_split() {
+        let item = item("org.example.App/StatusNotifierItem/1");
+        assert_eq!(item.bus_name, "org.example.App");
+        assert_eq!(item.object_path, "/StatusNotifierItem/1");
+        assert_eq!(
+            item.to_register_string(),
+            "org.example.App/StatusNotifierItem/1"
+        );
+    }
 }
@@ -0,0 +1,281 @@
+/**
+    tray (c) dotHQ 2021
+    A standalone tray application
+
+    The `(sa(iiay)ss)` ToolTip property of status notifier items. The description may
+    contain a small subset of html, which is converted into Pango markup for display.
+
+    This Source Code Form is subject to the terms of the Mozilla Public
+    License, v. 2.0. If a copy of the MPL was not distributed with this
+    file, You can obtain one at http://mozilla.org/MPL/2.0/.
+*/
+use crate::pixmap::{IconPixmap, RawPixmap};
+
+/// The raw representation of a tooltip on the bus
+pub type RawToolTip = (String, Vec<RawPixmap>, String, String);
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ToolTip {
+    pub icon_name: String,
+    pub icon_pixmap: Vec<IconPixmap>,
+    pub title: String,
+    pub description: String, // May contain html
+}
+
+impl ToolTip {
+    pub fn from_raw((icon_name, icon_pixmap, title, description): RawToolTip) -> Self {
+        ToolTip {
+            icon_name,
+            icon_pixmap: IconPixmap::from_raw(icon_pixmap),
+            title,
+            description,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.title.is_empty() && self.description.is_empty()
+    }
+
+    /// Pango markup with the title in bold, followed by the description
+    pub fn to_markup(&self) -> String {
+        let title = glib::markup_escape_text(self.title.trim()).to_string();
+        let description = html_to_pango_markup(&self.description);
+
+        match (title.is_empty(), description.is_empty()) {
+            (false, false) => format!("<b>{}</b>\n{}", title, description),
+            (false, true) => format!("<b>{}</b>", title),
+            _ => description,
+        }
+    }
+}
+
+/// Converts the html subset allowed in tooltips into Pango markup. Formatting tags that
+/// Pango knows are kept, line breaks and paragraphs become new lines, and everything else
+/// (links, images, unknown tags) is reduced to its text. Unbalanced tags are fixed up, as
+/// Pango refuses markup that is not well formed.
+pub fn html_to_pango_markup(html: &str) -> String {
+    let mut markup = String::with_capacity(html.len());
+    let mut open_tags: Vec<&'static str> = Vec::new();
+    let mut rest = html;
+
+    while let Some(index) = rest.find(['<', '&']) {
+        push_text(&mut markup, &rest[..index]);
+        rest = &rest[index..];
+
+        if rest.starts_with('&') {
+            // Keep entities that Pango understands, escape lone ampersands
+            match parse_entity(rest) {
+                Some((entity, length)) => {
+                    markup.push_str(&entity);
+                    rest = &rest[length..];
+                }
+                None => {
+                    markup.push_str("&amp;");
+                    rest = &rest[1..];
+                }
+            }
+            continue;
+        }
+
+        let starts_tag = rest[1..]
+            .chars()
+            .next()
+            .is_some_and(|c| c.is_ascii_alphabetic() || c == '/' || c == '!');
+
+        let end = match rest.find('>') {
+            Some(end) if starts_tag => end,
+            _ => {
+                // Not a tag after all
+                markup.push_str("&lt;");
+                rest = &rest[1..];
+                continue;
+            }
+        };
+
+        let tag = rest[1..end].trim();
+        rest = &rest[end + 1..];
+
+        let closing = tag.starts_with('/');
+        let name = tag
+            .trim_start_matches('/')
+            .split(|c: char| c.is_whitespace() || c == '/')
+            .next()
+            .unwrap_or("")
+            .to_lowercase();
+
+        match (name.as_str(), closing) {
+            ("br", _) => markup.push('\n'),
+            ("p", false) | ("div", false) => {
+                if !markup.is_empty() && !markup.ends_with('\n') {
+                    markup.push('\n');
+                }
+            }
+            ("p", true) | ("div", true) => markup.push('\n'),
+            (name, false) => {
+                if let Some(pango_tag) = pango_tag(name) {
+                    if !tag.ends_with('/') {
+                        markup.push_str(&format!("<{}>", pango_tag));
+                        open_tags.push(pango_tag);
+                    }
+                }
+            }
+            (name, true) => {
+                if let Some(pango_tag) = pango_tag(name) {
+                    // Close everything that was opened after this tag as well
+                    if let Some(position) = open_tags.iter().rposition(|open| *open == pango_tag) {
+                        for open in open_tags.drain(position..).rev() {
+                            markup.push_str(&format!("</{}>", open));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    push_text(&mut markup, rest);
+
+    for open in open_tags.into_iter().rev() {
+        markup.push_str(&format!("</{}>", open));
+    }
+
+    markup.trim_end().to_string()
+}
+
+/// The Pango tag for an html formatting tag
+fn pango_tag(name: &str) -> Option<&'static str> {
+    match name {
+        "b" | "strong" => Some("b"),
+        "i" | "em" => Some("i"),
+        "u" => Some("u"),
+        "s" | "strike" | "del" => Some("s"),
+        "big" => Some("big"),
+        "small" => Some("small"),
+        "sub" => Some("sub"),
+        "sup" => Some("sup"),
+        "tt" | "code" => Some("tt"),
+        _ => None,
+    }
+}
+
+/// Appends text, escaping the characters that have a meaning in markup
+fn push_text(markup: &mut String, text: &str) {
+    for c in text.chars() {
+        match c {
+            '<' => markup.push_str("&lt;"),
+            '>' => markup.push_str("&gt;"),
+            '"' => markup.push_str("&quot;"),
+            '\'' => markup.push_str("&apos;"),
+            c => markup.push(c),
+        }
+    }
+}
+
+/// Parses an entity at the start of `text`, returning its Pango equivalent and the length
+/// of the entity in `text`
+fn parse_entity(text: &str) -> Option<(String, usize)> {
+    let end = text.find(';')?;
+    let name = &text[1..end];
+
+    let entity = match name {
+        "amp" | "lt" | "gt" | "quot" | "apos" => format!("&{};", name),
+        "nbsp" => "\u{a0}".to_string(),
+        _ if name.starts_with('#') => {
+            // Character references are decoded, so that invalid ones cannot break the markup
+            let code = match name[1..].strip_prefix(['x', 'X']) {
+                Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                None => name[1..].parse::<u32>().ok()?,
+            };
+
+            let mut entity = String::new();
+            push_text(
+                &mut entity,
+                &std::char::from_u32(code)
+                    .filter(|c| *c != '\0')?
+                    .to_string(),
+            );
+            entity
+        }
+        _ => return None,
+    };
+
+    Some((entity, end + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_formatting_tags() {
+        assert_eq!(
+            html_to_pango_markup("<b>bold</b> <EM>italic</EM> <code>code</code>"),
+            "<b>bold</b> <i>italic</i> <tt>code</tt>"
+        );
+    }
+
+    #[test]
+    fn reduces_other_tags_to_their_text() {
+        assert_eq!(
+            html_to_pango_markup("<a href=\"https://dothq.co\">link</a><img src=\"x.png\">"),
+            "link"
+        );
+    }
+
+    #[test]
+    fn converts_line_breaks_and_paragraphs() {
+        assert_eq!(
+            html_to_pango_markup("one<br>two<br/>three<p>four</p>five"),
+            "one\ntwo\nthree\nfour\nfive"
+        );
+    }
+
+    #[test]
+    fn closes_unbalanced_tags() {
+        assert_eq!(
+            html_to_pango_markup("<b>bold <i>both"),
+            "<b>bold <i>both</i></b>"
+        );
+        assert_eq!(
+            html_to_pango_markup("<b>bold <i>both</b>"),
+            "<b>bold <i>both</i></b>"
+        );
+        assert_eq!(html_to_pango_markup("stray</b> close"), "stray close");
+    }
+
+    #[test]
+    fn skips_self_closing_tags() {
+        assert_eq!(html_to_pango_markup("a<b/>b<i />c"), "abc");
+    }
+
+    #[test]
+    fn escapes_stray_characters() {
+        assert_eq!(html_to_pango_markup("1 < 2 > 0"), "1 &lt; 2 &gt; 0");
+        assert_eq!(html_to_pango_markup("a<"), "a&lt;");
+        assert_eq!(html_to_pango_markup("<b unclosed"), "&lt;b unclosed");
+        assert_eq!(html_to_pango_markup("Tom & Jerry"), "Tom &amp; Jerry");
+        assert_eq!(html_to_pango_markup("&unknown; &"), "&amp;unknown; &amp;");
+    }
+
+    #[test]
+    fn keeps_known_entities() {
+        assert_eq!(
+            html_to_pango_markup("&lt;&amp;&gt;&nbsp;&quot;"),
+            "&lt;&amp;&gt;\u{a0}&quot;"
+        );
+    }
+
+    #[test]
+    fn decodes_numeric_entities() {
+        assert_eq!(parse_entity("&#65;rest"), Some(("A".to_string(), 5)));
+        assert_eq!(parse_entity("&#x263A;"), Some(("\u{263a}".to_string(), 8)));
+        assert_eq!(parse_entity("&#X3c;"), Some(("&lt;".to_string(), 6)));
+    }
+
+    #[test]
+    fn rejects_invalid_numeric_entities() {
+        assert_eq!(parse_entity("&#0;"), None);
+        assert_eq!(parse_entity("&#xD800;"), None);
+        assert_eq!(parse_entity("&#abc;"), None);
+        assert_eq!(parse_entity("&#65"), None);
+    }
+}